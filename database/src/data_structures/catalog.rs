@@ -0,0 +1,187 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use crate::data_structures::metadata::StreamMetadata;
+use crate::domain::UnixTime;
+use crate::storage::domain::data_page::DataPage;
+use crate::storage::error::StorageError;
+use crate::storage::file_system::FileSystem;
+use crate::storage::repage::{REPAGE_SUFFIX, REPLACED_SUFFIX};
+
+// Everything known about a stream on disk, found without opening it.
+#[derive(Clone, Debug)]
+pub struct StreamInfo {
+    // Relative to the root, with `/` between its parts.
+    pub name: String,
+    pub path: PathBuf,
+    pub metadata: StreamMetadata,
+    pub first: Option<UnixTime>,
+    pub last: Option<UnixTime>,
+    pub pages: usize,
+    // Records in page files. Deleted records are counted until they are
+    // purged, and records still in the write-ahead log aren't.
+    pub records: u64,
+    // Every file in the stream's directory and its shards, not only its pages.
+    pub bytes: u64
+}
+
+// The streams under a database root. Every directory holding stream
+// metadata is a stream, so this finds streams whether or not anything
+// currently running defines them.
+pub struct Catalog {
+    pub root: PathBuf,
+    pub streams: Vec<StreamInfo>
+}
+
+impl Catalog {
+    // Walks the root, reading page headers and the first and last record
+    // of each page. Nothing is written, so this is safe to run alongside
+    // an executor, though it may miss whatever is being flushed meanwhile.
+    pub fn scan(root: PathBuf) -> Result<Catalog, StorageError> {
+        let mut streams = Vec::new();
+        let mut pending = vec![root.clone()];
+
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir).map_err(|e| StorageError::io(&dir, e))? {
+                let entry = entry.map_err(|e| StorageError::io(&dir, e))?;
+                let file_type = entry.file_type().map_err(|e| StorageError::io(&entry.path(), e))?;
+
+                // A stream part way through being repaged isn't another stream.
+                let name = entry.file_name().to_string_lossy().to_string();
+                let repaging = name.ends_with(REPAGE_SUFFIX) || name.ends_with(REPLACED_SUFFIX);
+
+                if file_type.is_dir() && !repaging {
+                    pending.push(entry.path());
+                }
+            }
+
+            if let Some(metadata) = StreamMetadata::read(&dir)? {
+                streams.push(Self::stream_info(&root, dir, metadata)?);
+            }
+        }
+
+        streams.sort_by(|a, b| a.name.cmp(&b.name));
+
+        return Ok(Catalog {
+            root,
+            streams
+        });
+    }
+
+    fn stream_info(root: &PathBuf, path: PathBuf, metadata: StreamMetadata) -> Result<StreamInfo, StorageError> {
+        let name = path
+            .strip_prefix(root)
+            .unwrap()
+            .components()
+            .map(|v| v.as_os_str().to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join("/");
+
+        let mut info = StreamInfo {
+            name,
+            path: path.clone(),
+            metadata,
+            first: None,
+            last: None,
+            pages: 0,
+            records: 0,
+            bytes: 0
+        };
+
+        for entry in fs::read_dir(&path).map_err(|e| StorageError::io(&path, e))? {
+            let entry = entry.map_err(|e| StorageError::io(&path, e))?;
+
+            // Files come and go as the stream is flushed and compacted.
+            let file_metadata = match entry.metadata() {
+                Ok(v) => v,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(StorageError::io(&entry.path(), e))
+            };
+
+            if file_metadata.is_file() {
+                info.bytes += file_metadata.len();
+            }
+        }
+
+        for (_, page_path) in FileSystem::list_pages(&path)? {
+            // A page listed a moment ago may since have been compacted,
+            // purged or expired, and is skipped rather than failing the scan.
+            let page_metadata = match fs::metadata(&page_path) {
+                Ok(v) => v,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(StorageError::io(&page_path, e))
+            };
+
+            info.pages += 1;
+
+            // Sharded pages weren't counted with the rest of the directory.
+            if page_path.parent() != Some(path.as_path()) {
+                info.bytes += page_metadata.len();
+            }
+
+            if let Some(summary) = DataPage::summarise(&page_path)? {
+                info.records += summary.records;
+                info.first = Some(info.first.map_or(summary.first, |v| v.min(summary.first)));
+                info.last = Some(info.last.map_or(summary.last, |v| v.max(summary.last)));
+            }
+        }
+
+        return Ok(info);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&StreamInfo> {
+        return self.streams.iter().find(|v| v.name == name);
+    }
+
+    pub fn to_json(&self) -> String {
+        let streams = self.streams
+            .iter()
+            .map(|v| Self::stream_json(v))
+            .collect::<Vec<String>>();
+
+        return format!(
+            "{{\"root\":{},\"streams\":[{}]}}",
+            json_string(&self.root.to_string_lossy()),
+            streams.join(","));
+    }
+
+    fn stream_json(info: &StreamInfo) -> String {
+        let optional = |v: Option<UnixTime>| v.map_or("null".to_string(), |v| v.to_string());
+
+        let metadata = info.metadata.entries
+            .iter()
+            .map(|(k, v)| format!("{}:{}", json_string(k), json_string(v)))
+            .collect::<Vec<String>>();
+
+        return format!(
+            "{{\"name\":{},\"path\":{},\"first\":{},\"last\":{},\"pages\":{},\"records\":{},\"bytes\":{},\"metadata\":{{{}}}}}",
+            json_string(&info.name),
+            json_string(&info.path.to_string_lossy()),
+            optional(info.first),
+            optional(info.last),
+            info.pages,
+            info.records,
+            info.bytes,
+            metadata.join(","));
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c)
+        }
+    }
+
+    json.push('"');
+    return json;
+}
@@ -2,8 +2,18 @@ use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::iter;
 use std::ops::Deref;
+use crossbeam::channel::Sender;
 use uuid::Uuid;
 use crate::{Blob};
+use crate::domain::UnixTime;
+use crate::storage::domain::bucket::Bucketing;
+use crate::storage::domain::value::ValueType;
+use crate::storage::durability::Durability;
+use crate::storage::error::StorageError;
+use crate::storage::late_data::LatePolicy;
+use crate::storage::layout::Layout;
+use crate::storage::recovery::TornTailPolicy;
+use crate::storage::retention::Retention;
 use crate::streaming::domain::Calc;
 use crate::streaming::streams::stream::Stream;
 
@@ -37,7 +47,7 @@ impl MergedStreamRef {
         let close_blob = streams.get(&close).unwrap();
 
         let timestamp = close_blob.timestamp;
-        let hlc3 = (high_blob.data + low_blob.data + close_blob.data) / 3.0;
+        let hlc3 = (high_blob.data.to_f64() + low_blob.data.to_f64() + close_blob.data.to_f64()) / 3.0;
 
         return Blob::new(timestamp, hlc3);
     }
@@ -47,6 +57,13 @@ impl MergedStreamRef {
             MergedStreamRef::Hlc3 { .. } => 3
         }
     }
+
+    // Merges take any numeric input, but always average into a float.
+    pub fn output_type(&self) -> ValueType {
+        return match self {
+            MergedStreamRef::Hlc3 { .. } => ValueType::F64
+        }
+    }
 }
 
 
@@ -83,7 +100,14 @@ impl Deref for StreamRef {
 pub struct StreamDefinition {
     pub path: String,
     pub page_size: usize,
-    pub stream_kind: StreamKind
+    pub bucketing: Bucketing,
+    pub stream_kind: StreamKind,
+    pub late_policy: LatePolicy,
+    pub retention: Retention,
+    pub durability: Durability,
+    pub torn_tail: TornTailPolicy,
+    pub value_type: ValueType,
+    pub layout: Layout
 }
 
 impl Hash for StreamDefinition {
@@ -99,13 +123,67 @@ impl PartialEq for StreamDefinition {
 }
 
 impl StreamDefinition {
+    // Panics unless the page size is a positive number of milliseconds
+    // which fits in a timestamp.
     pub fn new(path: String, page_size: usize, stream_kind: StreamKind) -> StreamDefinition {
+        let bucketing = i64::try_from(page_size)
+            .ok()
+            .and_then(Bucketing::fixed)
+            .unwrap_or_else(|| panic!("Invalid page size {} for {}", page_size, path));
+
         return StreamDefinition {
             path,
             page_size,
-            stream_kind
+            bucketing,
+            stream_kind,
+            late_policy: LatePolicy::Reject,
+            retention: Retention::Forever,
+            durability: Durability::None,
+            torn_tail: TornTailPolicy::Truncate,
+            value_type: ValueType::F64,
+            layout: Layout::Flat
         };
     }
+
+    // Replaces the fixed page size with another way of bucketing pages.
+    pub fn with_bucketing(mut self, bucketing: Bucketing) -> StreamDefinition {
+        if let Bucketing::Fixed(interval) = bucketing {
+            assert!(interval > 0, "Invalid bucket interval {} for {}", interval, self.path);
+        }
+
+        self.bucketing = bucketing;
+        return self;
+    }
+
+    pub fn with_late_policy(mut self, late_policy: LatePolicy) -> StreamDefinition {
+        self.late_policy = late_policy;
+        return self;
+    }
+
+    pub fn with_retention(mut self, retention: Retention) -> StreamDefinition {
+        self.retention = retention;
+        return self;
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> StreamDefinition {
+        self.durability = durability;
+        return self;
+    }
+
+    pub fn with_torn_tail(mut self, torn_tail: TornTailPolicy) -> StreamDefinition {
+        self.torn_tail = torn_tail;
+        return self;
+    }
+
+    pub fn with_value_type(mut self, value_type: ValueType) -> StreamDefinition {
+        self.value_type = value_type;
+        return self;
+    }
+
+    pub fn with_layout(mut self, layout: Layout) -> StreamDefinition {
+        self.layout = layout;
+        return self;
+    }
 }
 
 pub struct Node {
@@ -125,8 +203,13 @@ impl Node {
 }
 
 pub enum Envelope {
-    Add(Vec<StreamRef>, StreamRef),
+    Add(Vec<StreamRef>, StreamRef, Sender<Result<(), StorageError>>),
     Flush(),
-    Data(StreamRef, Vec<Blob>)
+    Sync(),
+    Compact(),
+    EnforceRetention(),
+    Data(StreamRef, Vec<Blob>, Sender<Result<(), StorageError>>),
+    Upsert(StreamRef, Vec<Blob>, Sender<Result<(), StorageError>>),
+    Delete(StreamRef, UnixTime, UnixTime, Sender<Result<(), StorageError>>)
 }
 
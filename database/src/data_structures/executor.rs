@@ -1,41 +1,83 @@
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::path::Path;
 use std::rc::Rc;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
 use std::time::Duration;
 use crossbeam::channel::Sender;
+use log::error;
 use crate::{Blob, StreamRef, Vessel};
 use crate::data_structures::domain::{Envelope, Node, StreamDefinition};
 use crate::data_structures::graph::Graph;
 use crate::domain::UnixTime;
+use crate::storage::descriptors::DescriptorCache;
+use crate::storage::durability::Durability;
+use crate::storage::error::StorageError;
+use crate::storage::late_data::LateDataCounters;
+use crate::storage::lock::{LockMode, VesselLock};
+use crate::storage::reader::VesselReader;
 use crate::streaming::streams::stream::{create_stream, Stream};
+use crate::data_structures::metadata::StreamMetadata;
+use crate::storage::repage::finish_swap;
+
+const COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+const RETENTION_INTERVAL: Duration = Duration::from_secs(600);
 
 pub struct Executor {
     root: String,
     thread: thread::JoinHandle<()>,
     stream: Sender<Envelope>,
-    last_time: Arc<Mutex<Option<UnixTime>>>
+    last_time: Arc<Mutex<Option<UnixTime>>>,
+    late_data: Arc<Mutex<HashMap<StreamRef, LateDataCounters>>>,
+    readers: Arc<Mutex<HashMap<StreamRef, VesselReader>>>
 }
 
 impl Executor {
-    pub fn new(root: StreamRef, roots: Vec<StreamRef>, dir_path: String, buf_size: usize) -> Executor {
+    pub fn new(
+        root: StreamRef,
+        roots: Vec<StreamRef>,
+        dir_path: String,
+        buf_size: usize,
+        read_descriptors: usize) -> Result<Executor, StorageError> {
+
         let (sender, receiver) =
             crossbeam::channel::bounded::<Envelope>(buf_size);
+        let (ready_sender, ready) = crossbeam::channel::bounded(1);
 
         let local_dir_path = dir_path.clone();
         let last_time = Arc::new(Mutex::new(None));
         let last_clone = last_time.clone();
+        let late_data = Arc::new(Mutex::new(HashMap::new()));
+        let late_data_clone = late_data.clone();
+        let readers = Arc::new(Mutex::new(HashMap::new()));
+        let readers_clone = readers.clone();
+        // Files kept open for reading pages, shared by every stream.
+        let descriptors = Arc::new(DescriptorCache::new(read_descriptors));
+        let sync_sender = sender.clone();
 
         let thread = std::thread::spawn(move || {
             let mut graph = Graph::new(root);
             let mut last_var = None;
+            let mut group_commits = GroupCommits::new(sync_sender);
 
             for root in roots.clone() {
-                let (root_stream, last) = Self::create_stream(
-                    local_dir_path.clone().as_str(), root);
+                let created = Self::create_stream(
+                    local_dir_path.clone().as_str(), root, &[], &late_data, &readers, &descriptors);
+
+                // Nothing can run without its roots, so hand the failure
+                // back to Executor::new rather than carrying on.
+                let (root_stream, last) = match created {
+                    Ok(v) => v,
+                    Err(e) => {
+                        ready_sender.send(Err(e)).unwrap();
+                        return;
+                    }
+                };
 
                 graph.add(root, root_stream);
+                group_commits.add(root);
                 {
                     if last_var.is_none() {
                         last_var = Some(last);
@@ -53,45 +95,75 @@ impl Executor {
                 locked.replace(last_var.unwrap());
             }
 
+            ready_sender.send(Ok(())).unwrap();
+
 
             loop {
                 let msg = receiver.recv().unwrap();
 
                 match msg {
-                    Envelope::Add(sources, target) => {
-                        let (target_stream, last) = Self::create_stream(
-                            local_dir_path.clone().as_str(), target.clone());
-
-                        graph.add(target, target_stream);
-
-                        for source in sources {
-                            graph.subscribe(source, target);
+                    Envelope::Add(sources, target, reply) => {
+                        let result = Self::add_stream(
+                            &mut graph, local_dir_path.as_str(), sources, target, &late_data, &readers, &descriptors);
 
-                            let source_stream = &mut graph.get_stream(source);
-                            let it = source_stream.replay(last);
-
-                            for (_, batch) in it.enumerate() {
-                                graph.visit_from(
-                                    target,
-                                    Rc::new(batch),
-                                    |source, target, input| target.on_next(source, input));
-                            }
+                        if result.is_ok() {
+                            group_commits.add(target);
                         }
+
+                        // The caller may have stopped waiting.
+                        let _ = reply.send(result);
                     }
+                    // Maintenance has no caller waiting on it, so failures
+                    // are logged stream by stream. Each stream is visited
+                    // once, however many parents it has.
                     Envelope::Flush() => {
-                        for root in &roots {
-                            graph.visit_from(*root, Rc::new(vec![]), |source, target, input| {
-                                target.flush();
-                                return input;
-                            });
+                        graph.for_each(|stream, target| {
+                            if let Err(e) = target.flush() {
+                                error!("Flush of {} failed: {}", stream.path, e);
+                            }
+                        });
+                    },
+                    // Only streams which group their commits hold back syncs.
+                    Envelope::Sync() => {
+                        for stream in &group_commits.streams {
+                            if let Err(e) = graph.get_stream(*stream).tick() {
+                                error!("Sync of {} failed: {}", stream.path, e);
+                            }
                         }
                     },
-                    Envelope::Data(stream, data) => {
+                    Envelope::Compact() => {
+                        graph.for_each(|stream, target| {
+                            if let Err(e) = target.compact() {
+                                error!("Compaction of {} failed: {}", stream.path, e);
+                            }
+                        });
+                    },
+                    Envelope::EnforceRetention() => {
+                        graph.for_each(|stream, target| {
+                            if let Err(e) = target.enforce_retention() {
+                                error!("Retention of {} failed: {}", stream.path, e);
+                            }
+                        });
+                    },
+                    Envelope::Data(stream, data, reply) => {
                         let rc_data = Rc::new(data);
 
-                        graph.visit_from(stream, rc_data, |source, target,  input| {
+                        let result = graph.visit_from(stream, rc_data, |source, target,  input| {
                             return target.on_next(source,input);
-                        })
+                        });
+
+                        let _ = reply.send(result);
+                    }
+                    Envelope::Upsert(stream, data, reply) => {
+                        let result = graph.get_stream(stream)
+                            .upsert(Rc::new(data))
+                            .and_then(|changed| Self::recompute(&mut graph, stream, changed));
+
+                        let _ = reply.send(result);
+                    }
+                    Envelope::Delete(stream, from, to, reply) => {
+                        let result = graph.get_stream(stream).delete_range(from, to);
+                        let _ = reply.send(result);
                     }
                 }
             }
@@ -102,14 +174,38 @@ impl Executor {
             thread,
             stream: sender.clone(),
             last_time: last_clone,
+            late_data: late_data_clone,
+            readers: readers_clone
         };
 
+        ready.recv().unwrap()?;
+
+        let flush_sender = sender.clone();
         thread::spawn(move|| {
-            sender.send(Envelope::Flush()).unwrap();
+            flush_sender.send(Envelope::Flush()).unwrap();
             thread::sleep(Duration::from_secs(5));
         });
 
-        return executor;
+        // Maintenance runs in the background, on the executor thread, as
+        // that is the only thread which owns the vessels.
+        Self::schedule(sender.clone(), COMPACTION_INTERVAL, Envelope::Compact);
+        Self::schedule(sender, RETENTION_INTERVAL, Envelope::EnforceRetention);
+
+        return Ok(executor);
+    }
+
+    // Sends a message to the executor thread every interval, until the
+    // executor goes away.
+    fn schedule(sender: Sender<Envelope>, interval: Duration, message: fn() -> Envelope) {
+        thread::spawn(move|| {
+            loop {
+                thread::sleep(interval);
+
+                if sender.send(message()).is_err() {
+                    break;
+                }
+            }
+        });
     }
 
     pub fn get_last_time(&self) -> UnixTime {
@@ -123,29 +219,235 @@ impl Executor {
         }
     }
 
-    pub fn send_data(&self, source: StreamRef, data: Vec<Blob>) {
+    // Counters of the records each stream has rejected, reordered or
+    // rewritten because they arrived late.
+    pub fn late_data(&self, stream: StreamRef) -> Option<LateDataCounters> {
+        let locked = self.late_data.lock().unwrap();
+        return locked.get(&stream).cloned();
+    }
+
+    // A reader over a stream's stored records, which can be cloned and
+    // used from any thread without going through the executor.
+    pub fn reader(&self, stream: StreamRef) -> Option<VesselReader> {
+        let locked = self.readers.lock().unwrap();
+        return locked.get(&stream).cloned();
+    }
+
+    // Writes records to a stream and everything downstream of it, waiting
+    // for them to be stored. Late records rejected by a stream come back as
+    // LateData, with the rest of the batch stored and handed on.
+    pub fn send_data(&self, source: StreamRef, data: Vec<Blob>) -> Result<(), StorageError> {
+        let (reply, result) = crossbeam::channel::bounded(1);
+
+        self.stream
+            .send(Envelope::Data(source, data, reply))
+            .unwrap();
+
+        return result.recv().unwrap();
+    }
+
+    // Corrects records which may already be stored, recomputing anything
+    // downstream of them. Waits for the correction to be applied.
+    pub fn upsert(&self, source: StreamRef, data: Vec<Blob>) -> Result<(), StorageError> {
+        let (reply, result) = crossbeam::channel::bounded(1);
+
         self.stream
-            .send(Envelope::Data(source, data))
+            .send(Envelope::Upsert(source, data, reply))
             .unwrap();
+
+        return result.recv().unwrap();
     }
 
-    pub fn add(&self, source: Vec<StreamRef>, target: StreamRef) {
+    // Deletes a stream's records in [from, to). Streams derived from it
+    // keep what was computed from them.
+    pub fn delete_range(&self, stream: StreamRef, from: UnixTime, to: UnixTime) -> Result<(), StorageError> {
+        let (reply, result) = crossbeam::channel::bounded(1);
+
         self.stream
-            .send(Envelope::Add(source, target))
+            .send(Envelope::Delete(stream, from, to, reply))
             .unwrap();
+
+        return result.recv().unwrap();
+    }
+
+    // Adds a stream, replaying its sources into it. Waits for the stream
+    // to be opened and caught up.
+    pub fn add(&self, source: Vec<StreamRef>, target: StreamRef) -> Result<(), StorageError> {
+        let (reply, result) = crossbeam::channel::bounded(1);
+
+        self.stream
+            .send(Envelope::Add(source, target, reply))
+            .unwrap();
+
+        return result.recv().unwrap();
+    }
+
+    fn add_stream(
+        graph: &mut Graph,
+        root: &str,
+        sources: Vec<StreamRef>,
+        target: StreamRef,
+        late_data: &Mutex<HashMap<StreamRef, LateDataCounters>>,
+        readers: &Mutex<HashMap<StreamRef, VesselReader>>,
+        descriptors: &Arc<DescriptorCache>) -> Result<(), StorageError> {
+
+        // Check the wiring before anything is opened, so that a bad stream
+        // leaves the graph as it was.
+        for source in &sources {
+            Graph::check_types(*source, target)?;
+        }
+
+        let (target_stream, last) = Self::create_stream(root, target.clone(), &sources, late_data, readers, descriptors)?;
+
+        graph.add(target, target_stream);
+
+        for source in sources {
+            graph.subscribe(source, target)?;
+
+            let source_stream = &mut graph.get_stream(source);
+            let it = source_stream.replay(last);
+
+            // Each page goes to the target as it lies, and only what the
+            // target stores is handed on to anything downstream of it.
+            for page in it {
+                let mut page = Some(page?);
+
+                graph.visit_from(
+                    target,
+                    Rc::new(vec![]),
+                    |parent, stream, input| match page.take() {
+                        Some(page) => stream.on_replay(source, page),
+                        None => stream.on_next(parent, input)
+                    })?;
+            }
+        }
+
+        return Ok(());
+    }
+
+    // Walks the descendants of a corrected stream, recomputing each one
+    // from the affected range of all of its sources.
+    fn recompute(graph: &mut Graph, stream: StreamRef, changed: Rc<Vec<Blob>>) -> Result<(), StorageError> {
+        let mut pending = vec![(stream, changed)];
+
+        while let Some((source, changed)) = pending.pop() {
+            if changed.is_empty() {
+                continue;
+            }
+
+            let from = changed.iter().map(|v| v.timestamp).min().unwrap();
+            let to = changed.iter().map(|v| v.timestamp).max().unwrap();
+
+            for child in graph.children(source) {
+                let (start, end) = graph.get_stream(child).affected_range(from, to);
+                let mut inputs = Vec::new();
+
+                for parent in graph.parents(child) {
+                    let parent_stream = graph.get_stream(parent);
+                    parent_stream.flush()?;
+
+                    let mut batch = Vec::new();
+
+                    for page in parent_stream.read_range(start, end) {
+                        batch.extend(page?.iter());
+                    }
+
+                    inputs.push((parent, batch));
+                }
+
+                let output = graph.get_stream(child).on_correction(inputs)?;
+                pending.push((child, output));
+            }
+        }
+
+        return Ok(());
     }
 
-    fn create_stream(root: &str, def: StreamRef) -> (Box<dyn Stream>, UnixTime) {
+    fn create_stream(
+        root: &str,
+        def: StreamRef,
+        sources: &[StreamRef],
+        late_data: &Mutex<HashMap<StreamRef, LateDataCounters>>,
+        readers: &Mutex<HashMap<StreamRef, VesselReader>>,
+        descriptors: &Arc<DescriptorCache>) -> Result<(Box<dyn Stream>, UnixTime), StorageError> {
+
         let path = Path::new(root).join(&def.topic).join(&def.name);
 
-        let vessel = Vessel::new(
+        // Nothing in the stream's directory is touched until its lock is
+        // held, and a repage interrupted by a crash has to be finished before
+        // anything is created there.
+        let lock = VesselLock::acquire(&path, LockMode::Exclusive)?;
+        finish_swap(&path)?;
+
+        // Refuse to open a stream over data written under another definition.
+        StreamMetadata::new(&def, sources).open(&path)?;
+
+        let vessel = Vessel::with_lock(
             path,
-            def.page_size as i64);
+            def.bucketing,
+            def.late_policy,
+            def.retention,
+            def.durability,
+            def.torn_tail,
+            def.value_type,
+            def.layout,
+            descriptors.clone(),
+            lock)?;
+
+        let last_time = &vessel.get_last_time()?;
+        late_data.lock().unwrap().insert(def, vessel.late_data());
+        readers.lock().unwrap().insert(def, vessel.reader());
 
-        let last_time = &vessel.get_last_time();
         let stream = create_stream(def, vessel);
 
-        return (stream, last_time.clone());
+        return Ok((stream, last_time.clone()));
+    }
+}
+
+// The streams which group their commits. An idle one has nothing else to
+// make it sync, so they are ticked on a timer, which only runs once there
+// is one and runs as often as the shortest of their intervals.
+struct GroupCommits {
+    streams: Vec<StreamRef>,
+    interval: Arc<AtomicU64>,
+    sender: Sender<Envelope>
+}
+
+impl GroupCommits {
+    fn new(sender: Sender<Envelope>) -> GroupCommits {
+        return GroupCommits {
+            streams: Vec::new(),
+            interval: Arc::new(AtomicU64::new(u64::MAX)),
+            sender
+        };
+    }
+
+    fn add(&mut self, stream: StreamRef) {
+        let ms = match stream.durability {
+            Durability::GroupCommit(ms) => ms.max(1),
+            _ => return
+        };
+
+        self.streams.push(stream);
+
+        if self.interval.fetch_min(ms, Ordering::Relaxed) != u64::MAX {
+            return;
+        }
+
+        let sender = self.sender.clone();
+        let interval = self.interval.clone();
+
+        // Reads the interval each time round, as a stream added later may
+        // need syncing more often.
+        thread::spawn(move|| {
+            loop {
+                thread::sleep(Duration::from_millis(interval.load(Ordering::Relaxed)));
+
+                if sender.send(Envelope::Sync()).is_err() {
+                    break;
+                }
+            }
+        });
     }
 }
 
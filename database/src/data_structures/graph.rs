@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::rc::Rc;
-use crate::data_structures::domain::Node;
+use std::path::PathBuf;
+use crate::data_structures::domain::{Node, StreamKind};
 use crate::{Blob, StreamDefinition, StreamRef};
+use crate::storage::error::StorageError;
 use crate::streaming::streams::stream::Stream;
 
 pub struct Graph {
@@ -24,9 +26,35 @@ impl Graph {
         self.nodes.insert(stream_definition, node);
     }
 
-    pub fn subscribe(&mut self, source: StreamRef, target: StreamRef) {
+    pub fn subscribe(&mut self, source: StreamRef, target: StreamRef) -> Result<(), StorageError> {
+        Self::check_types(source, target)?;
+
         let mut source_node = self.nodes.get_mut(&source).unwrap();
         source_node.children.push(target);
+
+        return Ok(());
+    }
+
+    // Checks that the values a source hands out are ones the target can
+    // take, and that the target is declared with the type it will output.
+    pub fn check_types(source: StreamRef, target: StreamRef) -> Result<(), StorageError> {
+        let mismatch = |found| StorageError::TypeMismatch {
+            path: PathBuf::from(&target.path),
+            expected: target.value_type,
+            found
+        };
+
+        return match &target.stream_kind {
+            // Merges take any numeric input, but output a type of their own.
+            StreamKind::Merge(_) if !source.value_type.is_numeric() =>
+                Err(mismatch(source.value_type)),
+            StreamKind::Merge(kind) if target.value_type != kind.output_type() =>
+                Err(mismatch(kind.output_type())),
+            StreamKind::Merge(_) => Ok(()),
+            // Everything else hands its input values on as they are.
+            _ if source.value_type != target.value_type => Err(mismatch(source.value_type)),
+            _ => Ok(())
+        };
     }
 
     pub fn get_stream(&mut self, key: StreamRef) -> &mut Box<dyn Stream>{
@@ -34,26 +62,66 @@ impl Graph {
         return &mut node.stream;
     }
 
-    pub fn visit<F>(&mut self, source: StreamRef, data: Rc<Vec<Blob>>, mut visitor: F)
-        where F : FnMut(StreamRef, &mut Box<dyn Stream>, Rc<Vec<Blob>>) -> Rc<Vec<Blob>> {
+    pub fn children(&self, key: StreamRef) -> Vec<StreamRef> {
+        return self.nodes.get(&key).unwrap().children.clone();
+    }
+
+    pub fn parents(&self, key: StreamRef) -> Vec<StreamRef> {
+        return self.nodes
+            .values()
+            .filter(|node| node.children.contains(&key))
+            .map(|node| node.defn)
+            .collect();
+    }
+
+    pub fn visit<F>(&mut self, source: StreamRef, data: Rc<Vec<Blob>>, mut visitor: F) -> Result<(), StorageError>
+        where F : FnMut(StreamRef, &mut Box<dyn Stream>, Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError> {
         let idx = self.nodes.get(&source).unwrap();
 
-        self.visit_from(
+        return self.visit_from(
             idx.defn,
             data,
             visitor);
     }
 
-    pub fn visit_from<F>(&mut self, def: StreamRef, data: Rc<Vec<Blob>>, mut visitor: F)
-        where F : FnMut(StreamRef, &mut Box<dyn Stream>, Rc<Vec<Blob>>) -> Rc<Vec<Blob>> {
+    // Calls the visitor once for every stream, whatever its parents, for
+    // work which doesn't pass anything between streams.
+    pub fn for_each<F>(&mut self, mut visitor: F)
+        where F : FnMut(StreamRef, &mut Box<dyn Stream>) {
+
+        for node in self.nodes.values_mut() {
+            visitor(node.defn, &mut node.stream);
+        }
+    }
+
+    // Hands each stream's output on to its children. A stream which fails
+    // hands nothing on, but the rest of the graph is still visited, and the
+    // first failure is returned once it has been.
+    pub fn visit_from<F>(&mut self, def: StreamRef, data: Rc<Vec<Blob>>, mut visitor: F) -> Result<(), StorageError>
+        where F : FnMut(StreamRef, &mut Box<dyn Stream>, Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError> {
 
         let buf = &mut self.buf;
         buf.push((self.root, def, data));
 
+        let mut failed = None;
+
         while let Some((source_stream, target_stream, input_data)) = buf.pop() {
             let mut node = self.nodes.get_mut(&target_stream).unwrap();
             let output_stream = &mut node.stream;
-            let output = visitor(source_stream, output_stream, input_data);
+
+            let output = match visitor(source_stream, output_stream, input_data) {
+                Ok(v) => v,
+                // The rest of a batch with late records in it was stored.
+                Err(StorageError::LateData(e)) => {
+                    let accepted = Rc::new(e.accepted.clone());
+                    failed.get_or_insert(StorageError::LateData(e));
+                    accepted
+                }
+                Err(e) => {
+                    failed.get_or_insert(e);
+                    continue;
+                }
+            };
 
             for child in &node.children {
                 buf.push((target_stream, *child, output.clone()))
@@ -61,5 +129,10 @@ impl Graph {
         }
 
         buf.clear();
+
+        return match failed {
+            Some(e) => Err(e),
+            None => Ok(())
+        };
     }
 }
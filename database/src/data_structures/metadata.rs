@@ -0,0 +1,180 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use crate::data_structures::domain::{MergedStreamRef, StreamDefinition, StreamKind, StreamRef};
+use crate::storage::domain::data_page::DataPage;
+use crate::storage::error::StorageError;
+use crate::storage::file_system::METADATA_FILE_NAME;
+use crate::streaming::domain::Calc;
+
+// Settings which change how the files in a stream directory are read, or
+// what produced them. Opening a stream with any of these changed would
+// silently reinterpret or mix up its data, so is refused. Everything
+// else, such as the retention policy, is free to change between runs.
+const VALIDATED: [&str; 4] = ["bucketing", "kind", "value_type", "sources"];
+
+// The definition of a stream, kept as `key=value` lines in its directory
+// so that it can be checked on open, and read without the code which
+// created it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamMetadata {
+    pub entries: Vec<(String, String)>
+}
+
+impl StreamMetadata {
+    pub fn new(def: &StreamDefinition, sources: &[StreamRef]) -> StreamMetadata {
+        let sources = sources
+            .iter()
+            .map(|v| v.path.clone())
+            .collect::<Vec<String>>();
+
+        let entries = vec![
+            ("path", def.path.clone()),
+            ("bucketing", def.bucketing.to_string()),
+            ("kind", Self::kind(&def.stream_kind)),
+            ("value_type", def.value_type.to_string()),
+            ("sources", sources.join(",")),
+            ("late_policy", format!("{:?}", def.late_policy)),
+            ("retention", format!("{:?}", def.retention)),
+            ("durability", format!("{:?}", def.durability)),
+            ("torn_tail", format!("{:?}", def.torn_tail)),
+            ("layout", format!("{:?}", def.layout))
+        ];
+
+        return StreamMetadata {
+            entries: entries
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v))
+                .collect()
+        };
+    }
+
+    fn kind(kind: &StreamKind) -> String {
+        return match kind {
+            StreamKind::Source() => "source".to_string(),
+            StreamKind::Aggregate(calc, size, interval) => {
+                let calc = match calc {
+                    Calc::First => "first",
+                    Calc::Max => "max",
+                    Calc::Min => "min",
+                    Calc::Last => "last"
+                };
+
+                format!("aggregate({}, {}, {})", calc, size, interval)
+            }
+            StreamKind::Merge(MergedStreamRef::Hlc3 { high, low, close }) =>
+                format!("hlc3(high={}, low={}, close={})", high.path, low.path, close.path)
+        };
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        return self.entries
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str());
+    }
+
+    pub fn set(&mut self, key: &str, value: String) {
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some(entry) => entry.1 = value,
+            None => self.entries.push((key.to_string(), value))
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let mut text = String::new();
+
+        for (key, value) in &self.entries {
+            text.push_str(&format!("{}={}\n", key, value));
+        }
+
+        return text;
+    }
+
+    pub fn decode(text: &str) -> Option<StreamMetadata> {
+        let mut entries = Vec::new();
+
+        for line in text.lines().filter(|v| !v.is_empty()) {
+            let (key, value) = line.split_once('=')?;
+            entries.push((key.to_string(), value.to_string()));
+        }
+
+        return Some(StreamMetadata {
+            entries
+        });
+    }
+
+    // One line for every validated setting which differs from what the
+    // stream was created with.
+    pub fn diff(&self, on_disk: &StreamMetadata) -> Vec<String> {
+        let mut diff = Vec::new();
+
+        for key in VALIDATED {
+            let expected = self.get(key).unwrap_or("");
+            let found = on_disk.get(key).unwrap_or("");
+
+            if expected != found {
+                diff.push(format!("{}: created with '{}', now defined as '{}'", key, found, expected));
+            }
+        }
+
+        return diff;
+    }
+
+    pub fn read(dir: &PathBuf) -> Result<Option<StreamMetadata>, StorageError> {
+        let path = dir.join(METADATA_FILE_NAME);
+
+        let text = match fs::read_to_string(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(StorageError::io(&path, e))
+        };
+
+        let mut metadata = match Self::decode(&text) {
+            Some(v) => v,
+            None => return Err(StorageError::InvalidMetadata { path })
+        };
+
+        // Streams created before pages could be bucketed by the calendar
+        // only recorded their page size.
+        if metadata.get("bucketing").is_none() {
+            if let Some(page_size) = metadata.get("page_size").map(|v| v.to_string()) {
+                metadata.entries.retain(|(k, _)| k != "page_size");
+                metadata.set("bucketing", format!("fixed({})", page_size));
+            }
+        }
+
+        return Ok(Some(metadata));
+    }
+
+    // Writes the metadata of a new stream, or checks it against what is
+    // already in the directory. Settings which are free to change are
+    // brought up to date.
+    pub fn open(&self, dir: &PathBuf) -> Result<(), StorageError> {
+        if let Some(on_disk) = Self::read(dir)? {
+            let diff = self.diff(&on_disk);
+
+            if diff.len() > 0 {
+                return Err(StorageError::MetadataMismatch {
+                    path: dir.clone(),
+                    diff
+                });
+            }
+
+            if on_disk == *self {
+                return Ok(());
+            }
+        }
+
+        return self.write(dir);
+    }
+
+    pub fn write(&self, dir: &PathBuf) -> Result<(), StorageError> {
+        fs::create_dir_all(dir).map_err(|e| StorageError::io(dir, e))?;
+
+        let path = dir.join(METADATA_FILE_NAME);
+        DataPage::replace(&path, self.encode().as_bytes())?;
+
+        return Ok(());
+    }
+}
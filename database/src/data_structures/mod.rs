@@ -2,3 +2,5 @@ pub mod mailbox;
 pub mod executor;
 pub mod domain;
 pub mod graph;
+pub mod metadata;
+pub mod catalog;
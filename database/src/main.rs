@@ -15,6 +15,7 @@ use std::sync::Arc;
 use std::sync::atomic::AtomicPtr;
 use std::time::Duration;
 use tokio::time;
+use log::warn;
 use crate::storage::vessel2::Vessel;
 use bincode::{Encode,Decode};
 use chrono::{NaiveDateTime, Utc};
@@ -22,6 +23,8 @@ use crate::data_structures::domain::{MergedStreamRef, StreamDefinition, StreamKi
 use crate::data_structures::executor::{Executor};
 use crate::domain::UnixTime;
 use crate::storage::domain::blob::Blob;
+use crate::storage::descriptors::DEFAULT_READ_DESCRIPTORS;
+use crate::storage::error::StorageError;
 use crate::streaming::domain::{Calc};
 use crate::threading::ArcRead;
 
@@ -57,24 +60,37 @@ fn main() {
         root_def(),
         roots.clone(),
         root.to_string(),
-        10000);
+        10000,
+        DEFAULT_READ_DESCRIPTORS).unwrap();
 
 
     let last = executor.get_last_time();
+    let step = 60000;
 
-    for timestamp in (last..last+(60000*100000000)).step_by(60000) {
+    // The candle at the last time is already stored.
+    for timestamp in (last+step..last+(step*100000000)).step_by(step as usize) {
 
         let candle = create_ohlc(timestamp);
 
-        executor.send_data(o, vec![Blob::new(timestamp, candle.open)]);
-        executor.send_data(h, vec![Blob::new(timestamp, candle.high)]);
-        executor.send_data(l, vec![Blob::new(timestamp, candle.low)]);
-        executor.send_data(c, vec![Blob::new(timestamp, candle.close)]);
+        send(&executor, o, Blob::new(timestamp, candle.open));
+        send(&executor, h, Blob::new(timestamp, candle.high));
+        send(&executor, l, Blob::new(timestamp, candle.low));
+        send(&executor, c, Blob::new(timestamp, candle.close));
     }
 
     let _ = time::sleep(Duration::from_secs(100000));
 }
 
+// A root left behind the others by a crash rejects the candles it
+// already has, which are counted in its late data counters.
+fn send(executor: &Executor, stream: StreamRef, blob: Blob) {
+    match executor.send_data(stream, vec![blob]) {
+        Ok(()) => (),
+        Err(StorageError::LateData(e)) => warn!("{}", e),
+        Err(e) => panic!("Failed to write to {}: {}", stream.path, e)
+    }
+}
+
 pub fn leak<T>(item: T) -> &'static T {
     let leak = Box::leak(Box::new(item));
     return leak;
@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use crate::storage::domain::mapped_page::MappedPage;
+
+pub const DEFAULT_READ_DESCRIPTORS: usize = 64;
+
+// Files opened for reading pages, kept open between reads up to a cap,
+// with the least recently used closed first. One cache is shared by every
+// stream an executor runs, so the cap bounds them all together.
+//
+// A file is taken out while it's being read and put back afterwards, so
+// two threads never share one, and a page read by both at once just has
+// two files open.
+//
+// Sealed pages mapped into memory are kept the same way, up to the same
+// cap, but are shared rather than taken out.
+pub struct DescriptorCache {
+    cap: usize,
+    // Most recently used last.
+    files: Mutex<VecDeque<(PathBuf, File)>>,
+    mapped: Mutex<VecDeque<(PathBuf, Arc<MappedPage>)>>
+}
+
+impl DescriptorCache {
+    pub fn new(cap: usize) -> DescriptorCache {
+        return DescriptorCache {
+            cap,
+            files: Mutex::new(VecDeque::with_capacity(cap)),
+            mapped: Mutex::new(VecDeque::with_capacity(cap))
+        };
+    }
+
+    pub fn take(&self, path: &PathBuf) -> Option<File> {
+        let mut files = self.files.lock().unwrap();
+        let idx = files.iter().rposition(|(v, _)| v == path)?;
+
+        return files.remove(idx).map(|(_, file)| file);
+    }
+
+    pub fn put(&self, path: &PathBuf, file: File) {
+        if self.cap == 0 {
+            return;
+        }
+
+        let mut files = self.files.lock().unwrap();
+
+        if files.len() >= self.cap {
+            files.pop_front();
+        }
+
+        files.push_back((path.clone(), file));
+    }
+
+    pub fn mapped(&self, path: &PathBuf) -> Option<Arc<MappedPage>> {
+        let mut mapped = self.mapped.lock().unwrap();
+        let idx = mapped.iter().position(|(v, _)| v == path)?;
+
+        let entry = mapped.remove(idx)?;
+        let page = entry.1.clone();
+        mapped.push_back(entry);
+
+        return Some(page);
+    }
+
+    pub fn put_mapped(&self, path: &PathBuf, page: Arc<MappedPage>) {
+        if self.cap == 0 {
+            return;
+        }
+
+        let mut mapped = self.mapped.lock().unwrap();
+        mapped.retain(|(v, _)| v != path);
+
+        if mapped.len() >= self.cap {
+            mapped.pop_front();
+        }
+
+        mapped.push_back((path.clone(), page));
+    }
+
+    // Closes every file open on the path, and drops its mapping, as it's
+    // about to be replaced or removed and they'd go on reading what was
+    // there before.
+    pub fn forget(&self, path: &PathBuf) {
+        self.files.lock().unwrap().retain(|(v, _)| v != path);
+        self.mapped.lock().unwrap().retain(|(v, _)| v != path);
+    }
+
+    pub fn len(&self) -> usize {
+        return self.files.lock().unwrap().len();
+    }
+}
@@ -1,18 +1,19 @@
 use std::any::Any;
 use std::sync::{Arc, Mutex};
 use bincode::{Encode, Decode};
+use crate::storage::domain::value::Value;
 
 #[derive(Copy, Clone, Debug)]
 pub struct Blob {
     pub timestamp: i64,
-    pub data: f64
+    pub data: Value
 }
 
 impl Blob {
-    pub fn new(timestamp: i64, data: f64) -> Blob {
+    pub fn new(timestamp: i64, data: impl Into<Value>) -> Blob {
         return Blob {
             timestamp,
-            data
+            data: data.into()
         };
     }
 }
\ No newline at end of file
@@ -1,29 +1,226 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use chrono::{Datelike, DateTime, NaiveDate, TimeZone, Utc};
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+const WEEK: i64 = 7 * DAY;
+// The epoch fell on a Thursday, and weeks start on the Monday after.
+const FIRST_MONDAY: i64 = 4 * DAY;
+
+// How time is divided up into pages. Calendar buckets are in UTC.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Bucketing {
+    // A fixed number of milliseconds, aligned to the epoch.
+    Fixed(i64),
+    Daily,
+    // Starting on Mondays.
+    Weekly,
+    Monthly
+}
+
+impl Bucketing {
+    // None unless the interval is a positive number of milliseconds.
+    pub fn fixed(interval: i64) -> Option<Bucketing> {
+        return match interval {
+            v if v > 0 => Some(Bucketing::Fixed(v)),
+            _ => None
+        };
+    }
+
+    // The start of the bucket holding the given time. Times before the
+    // epoch round down too, into the bucket starting at or before them.
+    pub fn start(&self, time: i64) -> i64 {
+        return match self {
+            Bucketing::Fixed(interval) => time.saturating_sub(time.rem_euclid(*interval)),
+            Bucketing::Daily => {
+                let time = clamp(time);
+                time - time.rem_euclid(DAY)
+            },
+            Bucketing::Weekly => {
+                let time = clamp(time);
+                time - (time - FIRST_MONDAY).rem_euclid(WEEK)
+            },
+            Bucketing::Monthly => {
+                let date = to_utc(time).date_naive();
+                Self::millis(NaiveDate::from_ymd_opt(date.year(), date.month(), 1).unwrap())
+            }
+        };
+    }
+
+    // The start of the bucket after the one starting at `start`.
+    pub fn next(&self, start: i64) -> i64 {
+        return match self {
+            Bucketing::Fixed(interval) => start.saturating_add(*interval),
+            Bucketing::Daily => start.saturating_add(DAY),
+            Bucketing::Weekly => start.saturating_add(WEEK),
+            Bucketing::Monthly => {
+                let date = to_utc(start).date_naive();
+
+                let (year, month) = match date.month() {
+                    12 => (date.year() + 1, 1),
+                    month => (date.year(), month + 1)
+                };
+
+                // There's no month after the last one chrono can represent.
+                match NaiveDate::from_ymd_opt(year, month, 1) {
+                    Some(v) => Self::millis(v),
+                    None => i64::MAX
+                }
+            }
+        };
+    }
+
+    pub fn is_start(&self, time: i64) -> bool {
+        return self.start(time) == time;
+    }
+
+    fn millis(date: NaiveDate) -> i64 {
+        return date.and_hms_opt(0, 0, 0).unwrap().timestamp_millis();
+    }
+}
+
+// Calendar buckets only cover the dates chrono can represent, so times
+// beyond them are clamped into its first or last day.
+fn clamp(time: i64) -> i64 {
+    let min = NaiveDate::MIN.and_hms_opt(0, 0, 0).unwrap().timestamp_millis();
+    let max = NaiveDate::MAX.and_hms_opt(0, 0, 0).unwrap().timestamp_millis();
+
+    return time.clamp(min, max);
+}
+
+pub fn to_utc(time: i64) -> DateTime<Utc> {
+    return Utc.timestamp_millis_opt(clamp(time)).unwrap();
+}
+
+impl Display for Bucketing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Bucketing::Fixed(interval) => write!(f, "fixed({})", interval),
+            Bucketing::Daily => write!(f, "daily"),
+            Bucketing::Weekly => write!(f, "weekly"),
+            Bucketing::Monthly => write!(f, "monthly")
+        };
+    }
+}
+
+impl FromStr for Bucketing {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Bucketing, ()> {
+        return match s {
+            "daily" => Ok(Bucketing::Daily),
+            "weekly" => Ok(Bucketing::Weekly),
+            "monthly" => Ok(Bucketing::Monthly),
+            _ => {
+                let interval = s
+                    .strip_prefix("fixed(")
+                    .and_then(|v| v.strip_suffix(')'))
+                    .ok_or(())?;
+
+                let interval = interval.parse().map_err(|_| ())?;
+
+                Bucketing::fixed(interval).ok_or(())
+            }
+        };
+    }
+}
 
 #[derive(PartialEq, PartialOrd, Copy, Clone, Hash, Eq, Ord)]
 pub struct Bucket {
     pub val: i64,
-    pub interval: i64
+    pub bucketing: Bucketing
 }
 
 impl Bucket {
-    pub fn new(val: i64, interval: i64) -> Bucket {
-        return Bucket {val, interval };
+    pub fn new(val: i64, bucketing: Bucketing) -> Bucket {
+        return Bucket {val, bucketing };
     }
 
-    pub fn epoch(interval: i64) -> Bucket {
-        return Bucket { val: 0, interval };
+    pub fn epoch(bucketing: Bucketing) -> Bucket {
+        return Bucket { val: 0, bucketing };
     }
 
     pub fn next(&self) -> Bucket {
-        return Self::new(self.val + self.interval, self.interval);
+        return Self::new(self.bucketing.next(self.val), self.bucketing);
     }
 
-    pub fn for_time(val: i64, interval: i64) -> Bucket {
-        let start = val - (val % interval);
-
+    pub fn for_time(val: i64, bucketing: Bucketing) -> Bucket {
         return Bucket {
-            val: start,
-            interval
+            val: bucketing.start(val),
+            bucketing
         };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use super::{Bucketing, DAY};
+
+    fn at(year: i32, month: u32, day: u32, millis: i64) -> i64 {
+        return Bucketing::millis(NaiveDate::from_ymd_opt(year, month, day).unwrap()) + millis;
+    }
+
+    // Checks the bucket holding `time` and the one after it.
+    fn assert_bucket(bucketing: Bucketing, time: i64, start: i64, next: i64) {
+        assert_eq!(bucketing.start(time), start, "start of {} in {}", time, bucketing);
+        assert_eq!(bucketing.next(start), next, "after {} in {}", start, bucketing);
+        assert!(bucketing.is_start(start));
+    }
+
+    #[test]
+    fn daily_edges() {
+        let last = DAY - 1;
+
+        assert_bucket(Bucketing::Daily, at(2024, 1, 31, last), at(2024, 1, 31, 0), at(2024, 2, 1, 0));
+        assert_bucket(Bucketing::Daily, at(2024, 2, 1, 0), at(2024, 2, 1, 0), at(2024, 2, 2, 0));
+        assert_bucket(Bucketing::Daily, at(2024, 2, 29, 1), at(2024, 2, 29, 0), at(2024, 3, 1, 0));
+        assert_bucket(Bucketing::Daily, at(2023, 12, 31, last), at(2023, 12, 31, 0), at(2024, 1, 1, 0));
+        assert_bucket(Bucketing::Daily, at(2024, 1, 1, 0), at(2024, 1, 1, 0), at(2024, 1, 2, 0));
+    }
+
+    #[test]
+    fn weekly_edges() {
+        // 2024 started on a Monday, and 2025 on a Wednesday.
+        assert_bucket(Bucketing::Weekly, at(2023, 12, 31, DAY - 1), at(2023, 12, 25, 0), at(2024, 1, 1, 0));
+        assert_bucket(Bucketing::Weekly, at(2024, 1, 1, 0), at(2024, 1, 1, 0), at(2024, 1, 8, 0));
+        assert_bucket(Bucketing::Weekly, at(2025, 1, 1, 0), at(2024, 12, 30, 0), at(2025, 1, 6, 0));
+        assert_bucket(Bucketing::Weekly, at(2024, 3, 1, 0), at(2024, 2, 26, 0), at(2024, 3, 4, 0));
+    }
+
+    #[test]
+    fn monthly_edges() {
+        assert_bucket(Bucketing::Monthly, at(2024, 1, 31, DAY - 1), at(2024, 1, 1, 0), at(2024, 2, 1, 0));
+        assert_bucket(Bucketing::Monthly, at(2024, 2, 29, DAY - 1), at(2024, 2, 1, 0), at(2024, 3, 1, 0));
+        assert_bucket(Bucketing::Monthly, at(2023, 2, 28, DAY - 1), at(2023, 2, 1, 0), at(2023, 3, 1, 0));
+        assert_bucket(Bucketing::Monthly, at(2023, 12, 31, DAY - 1), at(2023, 12, 1, 0), at(2024, 1, 1, 0));
+        assert_bucket(Bucketing::Monthly, at(2024, 1, 1, 0), at(2024, 1, 1, 0), at(2024, 2, 1, 0));
+    }
+
+    #[test]
+    fn negative_timestamps() {
+        assert_bucket(Bucketing::Fixed(1000), -1, -1000, 0);
+        assert_bucket(Bucketing::Fixed(1000), -1000, -1000, 0);
+        assert_bucket(Bucketing::Fixed(1000), -1001, -2000, -1000);
+        assert_bucket(Bucketing::Fixed(1000), 0, 0, 1000);
+
+        // The epoch fell on a Thursday.
+        assert_bucket(Bucketing::Daily, -1, -DAY, 0);
+        assert_bucket(Bucketing::Weekly, -1, at(1969, 12, 29, 0), at(1970, 1, 5, 0));
+        assert_bucket(Bucketing::Monthly, -1, at(1969, 12, 1, 0), 0);
+        assert_bucket(Bucketing::Monthly, at(1900, 3, 1, -1), at(1900, 2, 1, 0), at(1900, 3, 1, 0));
+
+        assert_eq!(Bucketing::Fixed(1000).start(i64::MIN), i64::MIN);
+    }
+
+    #[test]
+    fn fixed_interval_must_be_positive() {
+        assert_eq!(Bucketing::fixed(1000), Some(Bucketing::Fixed(1000)));
+        assert_eq!(Bucketing::fixed(0), None);
+        assert_eq!(Bucketing::fixed(-1000), None);
+
+        assert_eq!("fixed(1000)".parse::<Bucketing>(), Ok(Bucketing::Fixed(1000)));
+        assert!("fixed(0)".parse::<Bucketing>().is_err());
+        assert!("fixed(-1000)".parse::<Bucketing>().is_err());
+    }
+}
@@ -1,115 +1,561 @@
+use std::fs;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::ops::Deref;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::DerefMut;
 use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 use crate::ArcRead;
+use crate::domain::UnixTime;
 use crate::storage::domain::blob::Blob;
 use crate::storage::domain::bucket::Bucket;
+use crate::storage::domain::gorilla;
+use crate::storage::domain::page_header::{HEADER_SIZE, PageEncoding, PageError, PageHeader};
+use crate::storage::domain::value::ValueType;
+use crate::storage::durability::SyncState;
+use crate::storage::error::StorageError;
 use crate::storage::file_handle::FileHandle;
 
 pub struct DataPage {
     pub bucket: Bucket,
     pub file: Arc<RwLock<FileHandle>>,
-    data: Vec<Blob>,
+    // Records not yet flushed. The buffer of the page being written to is
+    // shared with the vessel's readers, which merge it into what they read.
+    data: Arc<RwLock<Vec<Blob>>>,
+}
+
+// What a page file holds, without its records.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PageSummary {
+    pub records: u64,
+    pub first: UnixTime,
+    pub last: UnixTime,
+    pub bytes: u64
 }
 static NEWLINE: u8 = b'\n';
 
+// The count and checksum which precede a compressed block.
+const BLOCK_HEADER_SIZE: usize = 8;
+
+pub const TEMP_SUFFIX: &str = ".tmp";
+
 impl DataPage {
     pub fn open_page(bucket: Bucket, file: Arc<RwLock<FileHandle>>) -> DataPage {
+        return Self::with_buffer(bucket, file, Arc::new(RwLock::new(Vec::new())));
+    }
+
+    pub fn with_buffer(bucket: Bucket, file: Arc<RwLock<FileHandle>>, data: Arc<RwLock<Vec<Blob>>>) -> DataPage {
         return DataPage {
             bucket,
             file,
-            data: Vec::new()
+            data
+        };
+    }
+
+    pub fn read(&self) -> Result<Vec<Blob>, StorageError> {
+        let blobs = self.with_reader(|path, file| Self::read_file(path, file))?;
+
+        return match blobs.flatten() {
+            Some((_, blobs)) => Ok(blobs),
+            None => Ok(vec![])
         };
     }
 
-    pub fn read(&self) -> Vec<Blob> {
-        let handle_lock = self.file.read().unwrap();
+    // Runs a read against a file open on the page, which goes back to the
+    // page's cache of open files once the read succeeds. Returns None if
+    // the page has been created but not yet flushed, so has no file.
+    fn with_reader<T>(&self, read: impl FnOnce(&PathBuf, &mut File) -> Result<T, PageError>) -> Result<Option<T>, StorageError> {
+        let handle = self.file.read().unwrap();
+
+        let mut file = match handle.open_read()? {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+
+        let result = read(&handle.path, &mut file)?;
+        handle.release(file);
+
+        return Ok(Some(result));
+    }
+
+    // Reads a page file in either encoding, returning None if nothing
+    // has been written to it yet.
+    fn read_path(path: &PathBuf) -> Result<Option<(PageHeader, Vec<Blob>)>, PageError> {
+        // A page that has been created but not yet flushed has no file.
+        let mut file = match OpenOptions::new().read(true).open(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(PageError::io(path, e))
+        };
+
+        return Self::read_file(path, &mut file);
+    }
+
+    fn read_file(path: &PathBuf, file: &mut File) -> Result<Option<(PageHeader, Vec<Blob>)>, PageError> {
+        file.seek(SeekFrom::Start(0)).map_err(|e| PageError::io(path, e))?;
 
-        let file = self.open_read(handle_lock.path.clone());
         let mut reader = BufReader::new(file);
 
-        let mut bytes: [u8; 8] = [0,0,0,0,0,0,0,0];
-        let mut blobs = Vec::<Blob>::new();
+        let header = match PageHeader::read_from(path, &mut reader)? {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(|e| PageError::io(path, e))?;
+
+        let blobs = match header.encoding {
+            PageEncoding::Raw => Self::decode_raw(path, &header, HEADER_SIZE as u64, &bytes)?,
+            PageEncoding::Gorilla => Self::decode_compressed(path, &header, &bytes)?
+        };
+
+        return Ok(Some((header, blobs)));
+    }
+
+    // Decodes raw records, where offset is the position of the first one in the file.
+    fn decode_raw(path: &PathBuf, header: &PageHeader, offset: u64, bytes: &[u8]) -> Result<Vec<Blob>, PageError> {
+        let width = header.record_width();
+        let mut blobs = Vec::<Blob>::with_capacity(bytes.len() / width);
+
+        let mut records = bytes.chunks_exact(width);
+        let mut offset = offset;
+
+        for record in &mut records {
+            blobs.push(header.decode_record(path, offset, record)?);
+            offset += width as u64;
+        }
+
+        let remainder = records.remainder();
+        if remainder.len() > 0 {
+            return Err(PageError::TruncatedRecord {
+                path: path.clone(),
+                offset,
+                len: remainder.len()
+            });
+        }
+
+        return Ok(blobs);
+    }
+
+    pub fn decode_compressed(path: &PathBuf, header: &PageHeader, bytes: &[u8]) -> Result<Vec<Blob>, PageError> {
+        let offset = HEADER_SIZE as u64;
+
+        if bytes.len() < BLOCK_HEADER_SIZE {
+            return Err(PageError::TruncatedRecord { path: path.clone(), offset, len: bytes.len() });
+        }
+
+        let count = header.decode_u32(bytes[0..4].try_into().unwrap());
+        let expected = header.decode_u32(bytes[4..8].try_into().unwrap());
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&bytes[0..4]);
+        hasher.update(&bytes[BLOCK_HEADER_SIZE..]);
+        let found = hasher.finalize();
+
+        if expected != found {
+            return Err(PageError::Corrupt { path: path.clone(), offset, expected, found });
+        }
+
+        return gorilla::decode(&bytes[BLOCK_HEADER_SIZE..], count as usize, header.value_type)
+            .ok_or_else(|| PageError::TruncatedRecord {
+                path: path.clone(),
+                offset,
+                len: bytes.len()
+            });
+    }
+
+    // Reads the records with timestamps in [from, to). Raw records are fixed
+    // width and sorted, so the bounds are found by binary searching the file
+    // rather than decoding the whole page.
+    pub fn read_range(&self, from: UnixTime, to: UnixTime) -> Result<Vec<Blob>, StorageError> {
+        let blobs = self.with_reader(|path, file| {
+            let header = match Self::read_header(path, file)? {
+                Some(v) => v,
+                None => return Ok(vec![])
+            };
+
+            if header.encoding != PageEncoding::Raw {
+                let blobs = Self::read_file(path, file)?.map(|(_, blobs)| blobs).unwrap_or_default();
+
+                let start = blobs.partition_point(|blob| blob.timestamp < from);
+                let end = blobs.partition_point(|blob| blob.timestamp < to).max(start);
+
+                return Ok(blobs[start..end].to_vec());
+            }
+
+            let count = Self::record_count(path, &header, file)?;
+
+            let start = Self::lower_bound(path, &header, file, 0, count, from)?;
+            let end = Self::lower_bound(path, &header, file, start, count, to)?;
+
+            return Self::read_records(path, &header, file, start, end);
+        })?;
+
+        return Ok(blobs.unwrap_or_default());
+    }
+
+    // Reads the last `count` records with timestamps before `before`, in
+    // timestamp order. Raw records are fixed width, so this seeks straight
+    // to them rather than decoding the page from the start. Going by time
+    // rather than position keeps working while records are appended.
+    pub fn read_tail(&self, before: UnixTime, count: u64) -> Result<Vec<Blob>, StorageError> {
+        let blobs = self.with_reader(|path, file| {
+            let header = match Self::read_header(path, file)? {
+                Some(v) => v,
+                None => return Ok(vec![])
+            };
+
+            if header.encoding != PageEncoding::Raw {
+                let blobs = Self::read_file(path, file)?.map(|(_, blobs)| blobs).unwrap_or_default();
+
+                let end = blobs.partition_point(|blob| blob.timestamp < before);
+                let start = end.saturating_sub(count as usize);
 
-        loop {
-            let read_result = reader.read_exact(&mut bytes);
-            if let Err(e) = read_result {
-                break;
+                return Ok(blobs[start..end].to_vec());
             }
 
-            let timestamp = i64::from_ne_bytes(bytes);
+            let total = Self::record_count(path, &header, file)?;
 
-            reader.read_exact(&mut bytes).unwrap();
-            let value = f64::from_ne_bytes(bytes);
+            let end = Self::lower_bound(path, &header, file, 0, total, before)?;
+            let start = end.saturating_sub(count);
 
-            let blob = Blob::new(timestamp, value);
-            blobs.push(blob);
+            return Self::read_records(path, &header, file, start, end);
+        })?;
+
+        return Ok(blobs.unwrap_or_default());
+    }
+
+    // Summarises a page file without holding a handle to it, returning
+    // None if nothing has been written to it yet. A partial record at the
+    // end of a raw page is left out, as it would be on recovery.
+    pub fn summarise(path: &PathBuf) -> Result<Option<PageSummary>, StorageError> {
+        let (mut file, header) = match Self::open_header(path)? {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+
+        let bytes = file.metadata().map_err(|e| StorageError::io(path, e))?.len();
+
+        if header.encoding != PageEncoding::Raw {
+            let blobs = Self::read_path(path)?.map(|(_, blobs)| blobs).unwrap_or_default();
+
+            return Ok(match (blobs.first(), blobs.last()) {
+                (Some(first), Some(last)) => Some(PageSummary {
+                    records: blobs.len() as u64,
+                    first: first.timestamp,
+                    last: last.timestamp,
+                    bytes
+                }),
+                _ => None
+            });
+        }
+
+        let count = (bytes - HEADER_SIZE as u64) / header.record_width() as u64;
+
+        if count == 0 {
+            return Ok(None);
         }
 
-        return blobs;
+        let first = Self::read_records(path, &header, &mut file, 0, 1)?;
+        let last = Self::read_records(path, &header, &mut file, count - 1, count)?;
+
+        return Ok(Some(PageSummary {
+            records: count,
+            first: first[0].timestamp,
+            last: last[0].timestamp,
+            bytes
+        }));
+    }
+
+    // Opens a page file positioned after its header, returning None if
+    // nothing has been written to it yet.
+    fn open_header(path: &PathBuf) -> Result<Option<(File, PageHeader)>, PageError> {
+        let mut file = match File::open(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(PageError::io(path, e))
+        };
+
+        return Ok(PageHeader::read_from(path, &mut file)?.map(|header| (file, header)));
+    }
+
+    fn read_header(path: &PathBuf, file: &mut File) -> Result<Option<PageHeader>, PageError> {
+        file.seek(SeekFrom::Start(0)).map_err(|e| PageError::io(path, e))?;
+        return PageHeader::read_from(path, file);
+    }
+
+    // Reads the raw records with indexes in [start, end).
+    fn read_records(
+        path: &PathBuf,
+        header: &PageHeader,
+        file: &mut File,
+        start: u64,
+        end: u64) -> Result<Vec<Blob>, PageError> {
+
+        let width = header.record_width() as u64;
+        let offset = HEADER_SIZE as u64 + start * width;
+        let mut bytes = vec![0u8; ((end - start) * width) as usize];
+
+        file.seek(SeekFrom::Start(offset)).map_err(|e| PageError::io(path, e))?;
+        file.read_exact(&mut bytes).map_err(|e| PageError::io(path, e))?;
+
+        return Self::decode_raw(path, &header, offset, &bytes);
+    }
+
+    fn record_count(path: &PathBuf, header: &PageHeader, file: &File) -> Result<u64, PageError> {
+        let len = file.metadata().map_err(|e| PageError::io(path, e))?.len() - HEADER_SIZE as u64;
+        let width = header.record_width() as u64;
+
+        if len % width != 0 {
+            return Err(PageError::TruncatedRecord {
+                path: path.clone(),
+                offset: HEADER_SIZE as u64 + (len / width) * width,
+                len: (len % width) as usize
+            });
+        }
+
+        return Ok(len / width);
+    }
+
+    // Finds the index of the first record in [lo, hi) with a timestamp
+    // no earlier than the target, or hi if there is none.
+    fn lower_bound(
+        path: &PathBuf,
+        header: &PageHeader,
+        file: &mut File,
+        lo: u64,
+        hi: u64,
+        target: UnixTime) -> Result<u64, PageError> {
+
+        let width = header.record_width();
+        let mut bytes = vec![0u8; width];
+        let (mut lo, mut hi) = (lo, hi);
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let offset = HEADER_SIZE as u64 + mid * width as u64;
+
+            file.seek(SeekFrom::Start(offset)).map_err(|e| PageError::io(path, e))?;
+            file.read_exact(&mut bytes).map_err(|e| PageError::io(path, e))?;
+
+            let blob = header.decode_record(path, offset, &bytes)?;
+
+            if blob.timestamp < target {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        return Ok(lo);
+    }
+
+    // Rewrites a sealed raw page in the compressed encoding, returning
+    // whether anything was done.
+    pub fn compact(&self) -> Result<bool, StorageError> {
+        let mut handle_lock = self.file.write().unwrap();
+        let path = &handle_lock.path;
+
+        // Check the header first, to avoid decoding pages which are
+        // already compressed on every pass.
+        let mut file = match File::open(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(StorageError::io(path, e))
+        };
+
+        match PageHeader::read_from(path, &mut file)? {
+            Some(header) if header.encoding == PageEncoding::Raw => (),
+            _ => return Ok(false)
+        }
+
+        let (header, blobs) = Self::read_path(path)?.unwrap();
+
+        let header = header.with_encoding(PageEncoding::Gorilla);
+        let encoded = gorilla::encode(&blobs);
+        let count = header.encode_u32(blobs.len() as u32);
+
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&count);
+        hasher.update(&encoded);
+        let checksum = header.encode_u32(hasher.finalize());
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + BLOCK_HEADER_SIZE + encoded.len());
+        bytes.extend_from_slice(&header.encode());
+        bytes.extend_from_slice(&count);
+        bytes.extend_from_slice(&checksum);
+        bytes.extend_from_slice(&encoded);
+
+        Self::replace(path, &bytes)?;
+        handle_lock.replaced();
+
+        return Ok(true);
+    }
+
+    // Replaces the contents of the page with the given records, which must
+    // be in timestamp order. The page is left raw, even if it had been
+    // compressed, and will be compressed again on the next compaction.
+    pub fn rewrite(&self, records: &[Blob]) -> Result<(), StorageError> {
+        let mut handle_lock = self.file.write().unwrap();
+        let path = &handle_lock.path;
+
+        let header = match Self::open_header(path)? {
+            Some((_, header)) => header.with_encoding(PageEncoding::Raw),
+            None => PageHeader::current(handle_lock.value_type)
+        };
+
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + records.len() * header.record_width());
+        bytes.extend_from_slice(&header.encode());
+
+        for record in records {
+            header.encode_record(record, &mut bytes);
+        }
+
+        Self::replace(path, &bytes)?;
+        handle_lock.replaced();
+
+        return Ok(());
+    }
+
+    // Replaces a file through a temporary one, synced before it's renamed
+    // into place and with the directory synced after, so that a crash
+    // leaves either the old file or the new one rather than an empty or
+    // truncated file where a durable one used to be.
+    pub fn replace(path: &PathBuf, bytes: &[u8]) -> Result<(), PageError> {
+        let temp = Self::temp_path(path);
+
+        let mut file = File::create(&temp).map_err(|e| PageError::io(&temp, e))?;
+        file.write_all(bytes).map_err(|e| PageError::io(&temp, e))?;
+        file.sync_all().map_err(|e| PageError::io(&temp, e))?;
+        drop(file);
+
+        fs::rename(&temp, path).map_err(|e| PageError::io(path, e))?;
+
+        let dir = path.parent().unwrap().to_path_buf();
+
+        return File::open(&dir)
+            .and_then(|v| v.sync_all())
+            .map_err(|e| PageError::io(&dir, e));
+    }
+
+    pub fn temp_path(path: &PathBuf) -> PathBuf {
+        let mut name = path.file_name().unwrap().to_os_string();
+        name.push(TEMP_SUFFIX);
+
+        return path.with_file_name(name);
     }
 
     pub fn write(&mut self, record: Blob) {
-        self.data.push(record);
+        self.data.write().unwrap().push(record);
+    }
+
+    // Records written to this page which haven't been flushed yet.
+    pub fn pending(&self) -> Vec<Blob> {
+        return self.data.read().unwrap().clone();
     }
 
     pub fn update(
         mut self,
         bucket: Bucket,
-        file: Arc<RwLock<FileHandle>>) -> DataPage {
+        file: Arc<RwLock<FileHandle>>,
+        sync: &mut SyncState) -> Result<DataPage, StorageError> {
 
         // Move the vec out of self, so that the vec can be reused.
-        let mut vec: Vec<Blob>;
+        let vec: Arc<RwLock<Vec<Blob>>>;
         {
-            self.flush();
+            self.flush(sync)?;
+            self.file.write().unwrap().close_writer();
             vec = self.data;
-            vec.clear();
 
             // Self is dropped here
         }
 
-        return DataPage {
+        return Ok(DataPage {
             bucket,
             file,
             data: vec
-        }
+        });
     }
 
-    pub fn flush(&mut self) {
+    // Appends the buffered records to the page file, syncing it as the
+    // durability asks. They are only dropped from the buffer once written,
+    // so a failed flush can be retried.
+    pub fn flush(&mut self, sync: &mut SyncState) -> Result<(), StorageError> {
+        // Only the vessel changes the buffer, so readers can go on
+        // reading it until the records are on disk.
+        let data = self.data.read().unwrap();
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
         let mut guard = self.file.write().unwrap();
-        let handle = guard.deref();
+        let handle = guard.deref_mut();
 
-        let file = self.open_append(&handle.path);
+        // The file and its header are kept from one flush to the next.
+        if handle.writer().is_none() {
+            let file = self.open_append(&handle.path)?;
+            handle.set_writer(file);
+        }
 
-        let mut writer = BufWriter::new(&file);
+        if handle.header().is_none() {
+            let header = Self::prepare_header(&handle.path, handle.value_type, handle.writer().unwrap())?;
+            handle.set_header(header);
+        }
 
-        for blob in self.data.iter() {
+        let path = &handle.path;
+        let file = handle.writer().unwrap();
+        let header = handle.header().unwrap();
 
-            let timestamp = i64::to_ne_bytes(blob.timestamp);
-            let data = f64::to_ne_bytes(blob.data);
+        let mut writer = BufWriter::new(file);
+        let mut bytes = Vec::with_capacity(header.record_width());
 
-            writer.write(&timestamp).unwrap();
-            writer.write(&data).unwrap();
+        for blob in data.iter() {
+            bytes.clear();
+            header.encode_record(blob, &mut bytes);
+
+            writer.write_all(&bytes).map_err(|e| StorageError::io(path, e))?;
         }
 
-        self.data.clear();
+        writer.flush().map_err(|e| StorageError::io(path, e))?;
+        drop(writer);
+
+        drop(data);
+        self.data.write().unwrap().clear();
+
+        return sync.flushed(path, file);
     }
 
-    pub fn open_read(&self, path_buf: PathBuf) -> File {
-        return OpenOptions::new()
-            .read(true)
-            .open(path_buf.clone())
-            .unwrap();
+    // Writes the header to a new page file, or reads the existing one so that
+    // appended records use the same byte order as the rest of the file.
+    fn prepare_header(path: &PathBuf, value_type: ValueType, file: &File) -> Result<PageHeader, PageError> {
+        let len = file.metadata().map_err(|e| PageError::io(path, e))?.len();
+
+        if len == 0 {
+            let header = PageHeader::current(value_type);
+
+            let mut writer = file;
+            writer.write_all(&header.encode()).map_err(|e| PageError::io(path, e))?;
+
+            return Ok(header);
+        }
+
+        let mut reader = file;
+        let header = PageHeader::read_from(path, &mut reader)?.unwrap();
+
+        if header.encoding != PageEncoding::Raw {
+            return Err(PageError::Sealed { path: path.clone() });
+        }
+
+        return Ok(header);
     }
 
-    pub fn open_append(&self, path_buf: &PathBuf) -> File {
+    pub fn open_append(&self, path_buf: &PathBuf) -> Result<File, StorageError> {
         return OpenOptions::new()
+            .read(true)
             .write(true)
             .append(true)
             .create(true)
             .open(path_buf.clone())
-            .unwrap();
+            .map_err(|e| StorageError::io(path_buf, e));
     }
 }
\ No newline at end of file
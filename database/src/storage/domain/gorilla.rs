@@ -0,0 +1,380 @@
+use crate::storage::domain::blob::Blob;
+use crate::storage::domain::value::ValueType;
+
+// Compresses a run of records in the style of Facebook's Gorilla paper.
+// Timestamps are stored as a delta of deltas, which is a single bit for
+// regularly spaced data, and values are XORed with their predecessor so
+// that slowly changing values only store the bits which changed.
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: u8
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        return BitWriter {
+            bytes: Vec::new(),
+            bits: 0
+        };
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.bits == 0 {
+            self.bytes.push(0);
+        }
+
+        if bit {
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= 1 << (7 - self.bits);
+        }
+
+        self.bits = (self.bits + 1) % 8;
+    }
+
+    // Writes the lowest `count` bits of val, most significant first.
+    fn write_bits(&mut self, val: u64, count: u8) {
+        for i in (0..count).rev() {
+            self.write_bit((val >> i) & 1 == 1);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    pos: usize
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> BitReader<'a> {
+        return BitReader {
+            bytes,
+            pos: 0
+        };
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = self.bytes.get(self.pos / 8)?;
+        let bit = (byte >> (7 - (self.pos % 8))) & 1 == 1;
+
+        self.pos += 1;
+        return Some(bit);
+    }
+
+    fn read_bits(&mut self, count: u8) -> Option<u64> {
+        let mut val = 0u64;
+
+        for _ in 0..count {
+            val = (val << 1) | self.read_bit()? as u64;
+        }
+
+        return Some(val);
+    }
+}
+
+// Delta of delta buckets: (control bits, control length, value bits).
+const DOD_RANGES: [(u64, u8, u8); 3] = [
+    (0b10, 2, 7),
+    (0b110, 3, 9),
+    (0b1110, 4, 12)
+];
+
+fn fits(val: i64, bits: u8) -> bool {
+    let min = -(1i64 << (bits - 1));
+    let max = (1i64 << (bits - 1)) - 1;
+
+    return val >= min && val <= max;
+}
+
+fn sign_extend(val: u64, bits: u8) -> i64 {
+    let shift = 64 - bits as u32;
+    return ((val << shift) as i64) >> shift;
+}
+
+pub fn encode(blobs: &[Blob]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+
+    let mut prev_time = 0i64;
+    let mut prev_delta = 0i64;
+    let mut prev_value = 0u64;
+    let mut prev_leading = u8::MAX;
+    let mut prev_trailing = 0u8;
+
+    for (i, blob) in blobs.iter().enumerate() {
+        let value = blob.data.to_bits();
+
+        if i == 0 {
+            writer.write_bits(blob.timestamp as u64, 64);
+            writer.write_bits(value, 64);
+
+            prev_time = blob.timestamp;
+            prev_value = value;
+            continue;
+        }
+
+        let delta = blob.timestamp.wrapping_sub(prev_time);
+        let dod = delta.wrapping_sub(prev_delta);
+
+        if dod == 0 {
+            writer.write_bit(false);
+        } else {
+            match DOD_RANGES.iter().find(|(_, _, bits)| fits(dod, *bits)) {
+                Some((control, control_len, bits)) => {
+                    writer.write_bits(*control, *control_len);
+                    writer.write_bits(dod as u64, *bits);
+                }
+                None => {
+                    writer.write_bits(0b1111, 4);
+                    writer.write_bits(dod as u64, 64);
+                }
+            }
+        }
+
+        let xor = value ^ prev_value;
+
+        if xor == 0 {
+            writer.write_bit(false);
+        } else {
+            writer.write_bit(true);
+
+            // The leading count has to fit in 5 bits.
+            let leading = (xor.leading_zeros() as u8).min(31);
+            let trailing = xor.trailing_zeros() as u8;
+
+            if prev_leading != u8::MAX && leading >= prev_leading && trailing >= prev_trailing {
+                // The meaningful bits fit inside the previous window.
+                writer.write_bit(false);
+                writer.write_bits(xor >> prev_trailing, 64 - prev_leading - prev_trailing);
+            } else {
+                let meaningful = 64 - leading - trailing;
+
+                writer.write_bit(true);
+                writer.write_bits(leading as u64, 5);
+                // 64 meaningful bits doesn't fit in 6 bits, so is stored as 0.
+                writer.write_bits((meaningful % 64) as u64, 6);
+                writer.write_bits(xor >> trailing, meaningful);
+
+                prev_leading = leading;
+                prev_trailing = trailing;
+            }
+        }
+
+        prev_delta = delta;
+        prev_time = blob.timestamp;
+        prev_value = value;
+    }
+
+    return writer.bytes;
+}
+
+// Decodes `count` records, returning None if the stream ends early.
+pub fn decode(bytes: &[u8], count: usize, value_type: ValueType) -> Option<Vec<Blob>> {
+    let mut reader = BitReader::new(bytes);
+    let mut blobs = Vec::with_capacity(count);
+
+    let mut prev_time = 0i64;
+    let mut prev_delta = 0i64;
+    let mut prev_value = 0u64;
+    let mut prev_leading = 0u8;
+    let mut prev_trailing = 0u8;
+
+    for i in 0..count {
+        if i == 0 {
+            prev_time = reader.read_bits(64)? as i64;
+            prev_value = reader.read_bits(64)?;
+
+            blobs.push(Blob::new(prev_time, value_type.from_bits(prev_value)));
+            continue;
+        }
+
+        let mut control_len = 0;
+        while control_len < 4 && reader.read_bit()? {
+            control_len += 1;
+        }
+
+        let dod = match control_len {
+            0 => 0,
+            4 => reader.read_bits(64)? as i64,
+            n => {
+                let (_, _, bits) = DOD_RANGES[n - 1];
+                sign_extend(reader.read_bits(bits)?, bits)
+            }
+        };
+
+        let delta = prev_delta.wrapping_add(dod);
+        let timestamp = prev_time.wrapping_add(delta);
+
+        let mut value = prev_value;
+
+        if reader.read_bit()? {
+            if reader.read_bit()? {
+                prev_leading = reader.read_bits(5)? as u8;
+
+                let mut meaningful = reader.read_bits(6)? as u8;
+                if meaningful == 0 {
+                    meaningful = 64;
+                }
+
+                prev_trailing = 64 - prev_leading - meaningful;
+            }
+
+            let meaningful = 64 - prev_leading - prev_trailing;
+            let xor = reader.read_bits(meaningful)? << prev_trailing;
+
+            value = prev_value ^ xor;
+        }
+
+        blobs.push(Blob::new(timestamp, value_type.from_bits(value)));
+
+        prev_delta = delta;
+        prev_time = timestamp;
+        prev_value = value;
+    }
+
+    return Some(blobs);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::domain::blob::Blob;
+    use crate::storage::domain::value::{Decimal, Value, ValueType};
+    use super::{decode, encode};
+
+    fn round_trip(blobs: &[Blob], value_type: ValueType) {
+        let bytes = encode(blobs);
+        let decoded = decode(&bytes, blobs.len(), value_type).unwrap();
+
+        assert_eq!(decoded.len(), blobs.len());
+
+        for (expected, found) in blobs.iter().zip(&decoded) {
+            assert_eq!(found.timestamp, expected.timestamp);
+            assert_eq!(found.data.to_bits(), expected.data.to_bits());
+            assert_eq!(found.data.value_type(), value_type);
+        }
+    }
+
+    fn with_values(values: &[Value]) -> Vec<Blob> {
+        return values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| Blob::new(1_000 * i as i64, *v))
+            .collect();
+    }
+
+    #[test]
+    fn empty_and_single() {
+        round_trip(&[], ValueType::F64);
+        round_trip(&[Blob::new(i64::MIN, f64::NAN)], ValueType::F64);
+    }
+
+    #[test]
+    fn regular_series() {
+        let blobs: Vec<Blob> = (0..1000)
+            .map(|i| Blob::new(1_600_000_000_000 + i * 60_000, 42.5))
+            .collect();
+
+        round_trip(&blobs, ValueType::F64);
+    }
+
+    #[test]
+    fn every_delta_of_delta_range() {
+        // Deltas of deltas of 0, each control range at both ends, and ones
+        // which only fit in the 64 bit fallback.
+        let dods = [
+            0i64, 1, -1, 63, -64, 64, -65, 255, -256, 256, -257,
+            2047, -2048, 2048, -2049, 1 << 40, -(1 << 40)
+        ];
+
+        let mut timestamps = vec![0i64, 10];
+        for dod in dods {
+            let n = timestamps.len();
+            let delta = timestamps[n - 1] - timestamps[n - 2];
+            timestamps.push(timestamps[n - 1] + (delta + dod).max(1));
+        }
+
+        let blobs: Vec<Blob> = timestamps
+            .iter()
+            .map(|v| Blob::new(*v, *v as f64))
+            .collect();
+
+        round_trip(&blobs, ValueType::F64);
+    }
+
+    #[test]
+    fn extreme_timestamps() {
+        let blobs = [
+            Blob::new(i64::MIN, 1i64),
+            Blob::new(-1, 2i64),
+            Blob::new(0, 3i64),
+            Blob::new(i64::MAX, 4i64)
+        ];
+
+        round_trip(&blobs, ValueType::I64);
+    }
+
+    #[test]
+    fn leading_zeros_clamped() {
+        // XORs of small integers have more than 31 leading zeros.
+        let blobs = with_values(&[
+            Value::I64(0), Value::I64(1), Value::I64(3), Value::I64(2), Value::I64(1 << 40)
+        ]);
+
+        round_trip(&blobs, ValueType::I64);
+    }
+
+    #[test]
+    fn all_bits_meaningful() {
+        // An XOR with both its top and bottom bits set has 64 meaningful
+        // bits, which is stored as 0, followed by one inside its window.
+        let blobs = with_values(&[
+            Value::I64(0),
+            Value::I64(i64::MIN | 1),
+            Value::I64(i64::MIN | 3),
+            Value::I64(0),
+            Value::I64(-1)
+        ]);
+
+        round_trip(&blobs, ValueType::I64);
+    }
+
+    #[test]
+    fn every_value_type() {
+        let f64s = with_values(&[
+            Value::F64(0.0), Value::F64(-0.0), Value::F64(f64::NAN), Value::F64(f64::INFINITY),
+            Value::F64(f64::MIN_POSITIVE), Value::F64(1.5), Value::F64(1.25), Value::F64(f64::MAX)
+        ]);
+        round_trip(&f64s, ValueType::F64);
+
+        let i64s = with_values(&[
+            Value::I64(i64::MAX), Value::I64(i64::MIN), Value::I64(-1), Value::I64(0), Value::I64(7)
+        ]);
+        round_trip(&i64s, ValueType::I64);
+
+        let f32s = with_values(&[
+            Value::F32(0.5), Value::F32(-0.0), Value::F32(f32::NAN), Value::F32(f32::MAX), Value::F32(0.5)
+        ]);
+        round_trip(&f32s, ValueType::F32);
+
+        let bools = with_values(&[
+            Value::Bool(true), Value::Bool(true), Value::Bool(false), Value::Bool(true)
+        ]);
+        round_trip(&bools, ValueType::Bool);
+
+        let decimals = with_values(&[
+            Value::Decimal(Decimal::new(12345, 4)),
+            Value::Decimal(Decimal::new(-12345, 4)),
+            Value::Decimal(Decimal::new(i64::MAX, 4)),
+            Value::Decimal(Decimal::new(0, 4))
+        ]);
+        round_trip(&decimals, ValueType::Decimal(4));
+    }
+
+    #[test]
+    fn truncated_stream() {
+        let blobs = with_values(&[Value::F64(1.0), Value::F64(2.0), Value::F64(3.0)]);
+        let bytes = encode(&blobs);
+
+        assert!(decode(&bytes[..bytes.len() - 1], blobs.len(), ValueType::F64).is_none());
+        assert!(decode(&bytes, blobs.len() + 100, ValueType::F64).is_none());
+    }
+}
@@ -0,0 +1,307 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+use memmap2::Mmap;
+use crate::domain::UnixTime;
+use crate::storage::domain::blob::Blob;
+use crate::storage::domain::data_page::DataPage;
+use crate::storage::domain::page_header::{HEADER_SIZE, PageEncoding, PageError, PageHeader};
+
+// A sealed page file mapped into memory, whose records are decoded straight
+// from the mapping as they're read rather than copied into a buffer first.
+// Pages are only ever replaced by renaming a new file over them, so a
+// mapping carries on seeing the page as it was.
+pub struct MappedPage {
+    pub path: PathBuf,
+    pub header: PageHeader,
+    map: Mmap,
+    // Compressed pages can't be read in place, so are decoded up front,
+    // for as long as this mapping is in use.
+    decoded: Option<Vec<Blob>>
+}
+
+impl MappedPage {
+    // Returns None if the page has no file, or nothing has been written to it.
+    pub fn open(path: &PathBuf) -> Result<Option<MappedPage>, PageError> {
+        let mut file = match File::open(path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(PageError::io(path, e))
+        };
+
+        let header = match PageHeader::read_from(path, &mut file)? {
+            Some(v) => v,
+            None => return Ok(None)
+        };
+
+        // Safety: sealed pages are never written to or truncated in place.
+        let map = unsafe { Mmap::map(&file) }.map_err(|e| PageError::io(path, e))?;
+
+        let bytes = &map[HEADER_SIZE..];
+
+        let decoded = match header.encoding {
+            PageEncoding::Raw => {
+                let width = header.record_width();
+                let remainder = bytes.len() % width;
+
+                if remainder > 0 {
+                    return Err(PageError::TruncatedRecord {
+                        path: path.clone(),
+                        offset: (map.len() - remainder) as u64,
+                        len: remainder
+                    });
+                }
+
+                None
+            },
+            PageEncoding::Gorilla => Some(DataPage::decode_compressed(path, &header, bytes)?)
+        };
+
+        return Ok(Some(MappedPage {
+            path: path.clone(),
+            header,
+            map,
+            decoded
+        }));
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        return self.decoded.is_some();
+    }
+
+    // Checks the records in [start, end) against their checksums. Compressed
+    // pages were checked when they were decoded.
+    pub fn check(&self, start: usize, end: usize) -> Result<(), PageError> {
+        if self.decoded.is_some() {
+            return Ok(());
+        }
+
+        let width = self.header.record_width();
+
+        for idx in start..end {
+            let offset = (HEADER_SIZE + idx * width) as u64;
+            self.header.check_record(&self.path, offset, self.record(idx))?;
+        }
+
+        return Ok(());
+    }
+
+    pub fn len(&self) -> usize {
+        return match &self.decoded {
+            Some(v) => v.len(),
+            None => (self.map.len() - HEADER_SIZE) / self.header.record_width()
+        };
+    }
+
+    pub fn get(&self, idx: usize) -> Blob {
+        return match &self.decoded {
+            Some(v) => v[idx],
+            None => self.header.read_record(self.record(idx))
+        };
+    }
+
+    pub fn timestamp(&self, idx: usize) -> UnixTime {
+        return match &self.decoded {
+            Some(v) => v[idx].timestamp,
+            None => self.header.read_timestamp(self.record(idx))
+        };
+    }
+
+    fn record(&self, idx: usize) -> &[u8] {
+        let width = self.header.record_width();
+        let start = HEADER_SIZE + idx * width;
+
+        return &self.map[start..start + width];
+    }
+
+    // The index of the first record at or after `time`.
+    fn lower_bound(&self, time: UnixTime) -> usize {
+        let mut lo = 0;
+        let mut hi = self.len();
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+
+            if self.timestamp(mid) < time {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+
+        return lo;
+    }
+
+    pub fn view(self: &Arc<Self>) -> PageView {
+        return PageView::Mapped {
+            page: self.clone(),
+            start: 0,
+            end: self.len()
+        };
+    }
+}
+
+// A run of a page's records, in timestamp order. Mapped views share the
+// page's mapping, so slicing or cloning one copies nothing.
+#[derive(Clone)]
+pub enum PageView {
+    Mapped {
+        page: Arc<MappedPage>,
+        start: usize,
+        end: usize
+    },
+    // Records read through a file, or merged from the write buffer.
+    Buffered(Vec<Blob>)
+}
+
+impl PageView {
+    pub fn empty() -> PageView {
+        return PageView::Buffered(vec![]);
+    }
+
+    pub fn len(&self) -> usize {
+        return match self {
+            PageView::Mapped { start, end, .. } => end - start,
+            PageView::Buffered(v) => v.len()
+        };
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.len() == 0;
+    }
+
+    pub fn get(&self, idx: usize) -> Blob {
+        return match self {
+            PageView::Mapped { page, start, .. } => page.get(start + idx),
+            PageView::Buffered(v) => v[idx]
+        };
+    }
+
+    pub fn first(&self) -> Option<Blob> {
+        return if self.is_empty() { None } else { Some(self.get(0)) };
+    }
+
+    pub fn last(&self) -> Option<Blob> {
+        return if self.is_empty() { None } else { Some(self.get(self.len() - 1)) };
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item=Blob> + '_ {
+        return (0..self.len()).map(move |idx| self.get(idx));
+    }
+
+    // The records with timestamps in [from, to).
+    pub fn range(self, from: UnixTime, to: UnixTime) -> PageView {
+        return match self {
+            PageView::Mapped { page, start, end } => {
+                let lo = page.lower_bound(from).clamp(start, end);
+                let hi = page.lower_bound(to).clamp(lo, end);
+
+                PageView::Mapped { page, start: lo, end: hi }
+            },
+            PageView::Buffered(mut data) => {
+                data.retain(|v| v.timestamp >= from && v.timestamp < to);
+                PageView::Buffered(data)
+            }
+        };
+    }
+
+    // Records are checked every time they're read, as they are when read
+    // through a file, rather than only when the page is first mapped.
+    pub fn check(&self) -> Result<(), PageError> {
+        return match self {
+            PageView::Mapped { page, start, end } => page.check(*start, *end),
+            PageView::Buffered(_) => Ok(())
+        };
+    }
+
+    pub fn into_vec(self) -> Vec<Blob> {
+        return match self {
+            PageView::Mapped { .. } => self.iter().collect(),
+            PageView::Buffered(v) => v
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::{Arc, RwLock};
+    use crate::domain::UnixTime;
+    use crate::storage::descriptors::DescriptorCache;
+    use crate::storage::domain::blob::Blob;
+    use crate::storage::domain::bucket::{Bucket, Bucketing};
+    use crate::storage::domain::data_page::DataPage;
+    use crate::storage::domain::value::{Value, ValueType};
+    use crate::storage::durability::Durability;
+    use crate::storage::file_handle::FileHandle;
+    use crate::storage::late_data::LatePolicy;
+    use crate::storage::layout::Layout;
+    use crate::storage::recovery::TornTailPolicy;
+    use crate::storage::retention::Retention;
+    use crate::storage::testing::TempDir;
+    use crate::storage::vessel2::Vessel;
+
+    const BUCKETING: Bucketing = Bucketing::Fixed(1000);
+
+    // Writes records into a few pages, all but the last of them sealed.
+    fn write_pages(path: &PathBuf, descriptors: &Arc<DescriptorCache>) {
+        let mut vessel = Vessel::new(
+            path.clone(),
+            BUCKETING,
+            LatePolicy::Reject,
+            Retention::Forever,
+            Durability::None,
+            TornTailPolicy::Truncate,
+            ValueType::F64,
+            Layout::Flat,
+            descriptors.clone()).unwrap();
+
+        vessel.write(Rc::new((0..2500).map(|t| Blob::new(t * 3 / 2, (t as f64).sqrt())).collect())).unwrap();
+        vessel.flush().unwrap();
+    }
+
+    fn records(blobs: impl Iterator<Item=Blob>) -> Vec<(UnixTime, Value)> {
+        return blobs.map(|v| (v.timestamp, v.data)).collect();
+    }
+
+    fn assert_view_matches_read(page: &DataPage, handle: &Arc<RwLock<FileHandle>>, compressed: bool) {
+        let mapped = handle.read().unwrap().mapped().unwrap().unwrap();
+        assert_eq!(mapped.is_compressed(), compressed);
+
+        let view = mapped.view();
+        let read = page.read().unwrap();
+
+        assert!(!read.is_empty());
+        view.check().unwrap();
+        assert_eq!(records(view.iter()), records(read.into_iter()));
+
+        let range = view.range(1100, 1400);
+        range.check().unwrap();
+        assert_eq!(records(range.iter()), records(page.read_range(1100, 1400).unwrap().into_iter()));
+    }
+
+    #[test]
+    fn view_matches_read() {
+        let dir = TempDir::new("mapped_page");
+        let path = dir.join("stream");
+        let descriptors = Arc::new(DescriptorCache::new(8));
+
+        write_pages(&path, &descriptors);
+
+        let bucket = Bucket::new(1000, BUCKETING);
+        let handle = Arc::new(RwLock::new(FileHandle::new(
+            Layout::Flat.page_path(&path, bucket.val),
+            bucket,
+            ValueType::F64,
+            descriptors)));
+
+        let page = DataPage::open_page(bucket, handle.clone());
+
+        assert_view_matches_read(&page, &handle, false);
+
+        assert!(page.compact().unwrap());
+        assert_view_matches_read(&page, &handle, true);
+    }
+}
@@ -1,4 +1,8 @@
 pub mod record;
 pub mod blob;
 pub mod bucket;
-pub mod data_page;
\ No newline at end of file
+pub mod data_page;
+pub mod mapped_page;
+pub mod gorilla;
+pub mod page_header;
+pub mod value;
@@ -0,0 +1,427 @@
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Read;
+use std::path::PathBuf;
+use crate::domain::UnixTime;
+use crate::storage::domain::blob::Blob;
+use crate::storage::domain::value::ValueType;
+
+// Every page file starts with a fixed size header so that it can be
+// identified, versioned and decoded on a machine with a different
+// byte order to the one that wrote it.
+//
+// | magic (4) | version (1) | byte order (1) | ts unit (1) | layout (1) | encoding (1) |
+// | value type (1) | decimal scale (1) | reserved (5) |
+//
+// A raw page is followed by fixed width records, each one carrying a
+// CRC32 of its payload so that corruption is caught on read.
+//
+// | payload (layout.record_size()) | crc32 (4) |
+//
+// A sealed page may instead be compressed, in which case the header is
+// followed by a single block covered by one checksum.
+//
+// | count (4) | crc32 (4) | gorilla encoded records |
+pub const MAGIC: [u8; 4] = *b"RVSL";
+pub const FORMAT_VERSION: u8 = 2;
+pub const HEADER_SIZE: usize = 16;
+pub const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big
+}
+
+impl ByteOrder {
+    pub fn native() -> ByteOrder {
+        if cfg!(target_endian = "big") {
+            return ByteOrder::Big;
+        }
+
+        return ByteOrder::Little;
+    }
+
+    fn to_byte(&self) -> u8 {
+        return match self {
+            ByteOrder::Little => 0,
+            ByteOrder::Big => 1
+        };
+    }
+
+    fn from_byte(val: u8) -> Option<ByteOrder> {
+        return match val {
+            0 => Some(ByteOrder::Little),
+            1 => Some(ByteOrder::Big),
+            _ => None
+        };
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TimestampUnit {
+    Millis
+}
+
+impl TimestampUnit {
+    fn to_byte(&self) -> u8 {
+        return match self {
+            TimestampUnit::Millis => 0
+        };
+    }
+
+    fn from_byte(val: u8) -> Option<TimestampUnit> {
+        return match val {
+            0 => Some(TimestampUnit::Millis),
+            _ => None
+        };
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RecordLayout {
+    // An i64 timestamp followed by an 8 byte value, of the type given
+    // in the header.
+    TimestampValue
+}
+
+impl RecordLayout {
+    pub fn record_size(&self) -> usize {
+        return match self {
+            RecordLayout::TimestampValue => 16
+        };
+    }
+
+    fn to_byte(&self) -> u8 {
+        return match self {
+            RecordLayout::TimestampValue => 1
+        };
+    }
+
+    fn from_byte(val: u8) -> Option<RecordLayout> {
+        return match val {
+            1 => Some(RecordLayout::TimestampValue),
+            _ => None
+        };
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PageEncoding {
+    Raw,
+    Gorilla
+}
+
+impl PageEncoding {
+    fn to_byte(&self) -> u8 {
+        return match self {
+            PageEncoding::Raw => 0,
+            PageEncoding::Gorilla => 1
+        };
+    }
+
+    fn from_byte(val: u8) -> Option<PageEncoding> {
+        return match val {
+            0 => Some(PageEncoding::Raw),
+            1 => Some(PageEncoding::Gorilla),
+            _ => None
+        };
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PageHeader {
+    pub version: u8,
+    pub byte_order: ByteOrder,
+    pub timestamp_unit: TimestampUnit,
+    pub record_layout: RecordLayout,
+    pub encoding: PageEncoding,
+    pub value_type: ValueType
+}
+
+impl PageHeader {
+    // The header written to new page files by this build.
+    pub fn current(value_type: ValueType) -> PageHeader {
+        return PageHeader {
+            version: FORMAT_VERSION,
+            byte_order: ByteOrder::native(),
+            timestamp_unit: TimestampUnit::Millis,
+            record_layout: RecordLayout::TimestampValue,
+            encoding: PageEncoding::Raw,
+            value_type
+        };
+    }
+
+    pub fn with_encoding(&self, encoding: PageEncoding) -> PageHeader {
+        let mut header = *self;
+        header.encoding = encoding;
+
+        return header;
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut bytes = [0u8; HEADER_SIZE];
+
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = self.byte_order.to_byte();
+        bytes[6] = self.timestamp_unit.to_byte();
+        bytes[7] = self.record_layout.to_byte();
+        bytes[8] = self.encoding.to_byte();
+        bytes[9..11].copy_from_slice(&self.value_type.to_bytes());
+
+        return bytes;
+    }
+
+    pub fn decode(path: &PathBuf, bytes: &[u8; HEADER_SIZE]) -> Result<PageHeader, PageError> {
+        if bytes[0..4] != MAGIC {
+            return Err(PageError::UnknownFormat { path: path.clone() });
+        }
+
+        let version = bytes[4];
+        if version != FORMAT_VERSION {
+            return Err(PageError::UnsupportedVersion { path: path.clone(), version });
+        }
+
+        let invalid = |field: &'static str, value: u8| PageError::InvalidHeader {
+            path: path.clone(),
+            field,
+            value
+        };
+
+        let byte_order = ByteOrder::from_byte(bytes[5])
+            .ok_or_else(|| invalid("byte order", bytes[5]))?;
+
+        let timestamp_unit = TimestampUnit::from_byte(bytes[6])
+            .ok_or_else(|| invalid("timestamp unit", bytes[6]))?;
+
+        let record_layout = RecordLayout::from_byte(bytes[7])
+            .ok_or_else(|| invalid("record layout", bytes[7]))?;
+
+        let encoding = PageEncoding::from_byte(bytes[8])
+            .ok_or_else(|| invalid("encoding", bytes[8]))?;
+
+        let value_type = ValueType::from_bytes([bytes[9], bytes[10]])
+            .ok_or_else(|| invalid("value type", bytes[9]))?;
+
+        return Ok(PageHeader {
+            version,
+            byte_order,
+            timestamp_unit,
+            record_layout,
+            encoding,
+            value_type
+        });
+    }
+
+    // Reads and validates the header at the start of a page file. An empty
+    // file has no header yet, in which case None is returned.
+    pub fn read_from<R: Read>(path: &PathBuf, reader: &mut R) -> Result<Option<PageHeader>, PageError> {
+        let mut bytes = [0u8; HEADER_SIZE];
+        let mut read = 0;
+
+        while read < HEADER_SIZE {
+            let count = reader
+                .read(&mut bytes[read..])
+                .map_err(|e| PageError::io(path, e))?;
+
+            if count == 0 {
+                break;
+            }
+
+            read += count;
+        }
+
+        if read == 0 {
+            return Ok(None);
+        }
+
+        if read < HEADER_SIZE {
+            return Err(PageError::TruncatedHeader { path: path.clone(), len: read });
+        }
+
+        let header = Self::decode(path, &bytes)?;
+        header.check_compatible(path)?;
+
+        return Ok(Some(header));
+    }
+
+    // A page may have been written on a machine with a different byte order,
+    // which is fine, but the unit and layout must match what we decode.
+    pub fn check_compatible(&self, path: &PathBuf) -> Result<(), PageError> {
+        let expected = PageHeader::current(self.value_type);
+
+        if self.timestamp_unit != expected.timestamp_unit || self.record_layout != expected.record_layout {
+            return Err(PageError::Mismatch {
+                path: path.clone(),
+                expected,
+                found: *self
+            });
+        }
+
+        return Ok(());
+    }
+
+    // Pages of a stream must all hold the type of value it was declared with.
+    pub fn check_type(&self, path: &PathBuf, expected: ValueType) -> Result<(), PageError> {
+        if self.value_type != expected {
+            return Err(PageError::WrongType {
+                path: path.clone(),
+                expected,
+                found: self.value_type
+            });
+        }
+
+        return Ok(());
+    }
+
+    // The on-disk width of a single record, including its checksum.
+    pub fn record_width(&self) -> usize {
+        return self.record_layout.record_size() + CHECKSUM_SIZE;
+    }
+
+    pub fn encode_record(&self, blob: &Blob, out: &mut Vec<u8>) {
+        let start = out.len();
+
+        out.extend_from_slice(&self.encode_i64(blob.timestamp));
+        out.extend_from_slice(&self.encode_u64(blob.data.to_bits()));
+
+        let checksum = crc32fast::hash(&out[start..]);
+        out.extend_from_slice(&self.encode_u32(checksum));
+    }
+
+    // Decodes a single record. The offset is the record's position in the
+    // page file and is only used to report where corruption was found.
+    pub fn decode_record(&self, path: &PathBuf, offset: u64, bytes: &[u8]) -> Result<Blob, PageError> {
+        self.check_record(path, offset, bytes)?;
+        return Ok(self.read_record(bytes));
+    }
+
+    pub fn check_record(&self, path: &PathBuf, offset: u64, bytes: &[u8]) -> Result<(), PageError> {
+        let size = self.record_layout.record_size();
+        let (payload, checksum) = bytes.split_at(size);
+
+        let expected = self.decode_u32(checksum.try_into().unwrap());
+        let found = crc32fast::hash(payload);
+
+        if expected != found {
+            return Err(PageError::Corrupt {
+                path: path.clone(),
+                offset,
+                expected,
+                found
+            });
+        }
+
+        return Ok(());
+    }
+
+    // Decodes a record without checking it, for records already checked.
+    pub fn read_record(&self, bytes: &[u8]) -> Blob {
+        let value = self.decode_u64(bytes[8..16].try_into().unwrap());
+
+        return Blob::new(self.read_timestamp(bytes), self.value_type.from_bits(value));
+    }
+
+    pub fn read_timestamp(&self, bytes: &[u8]) -> UnixTime {
+        return self.decode_i64(bytes[0..8].try_into().unwrap());
+    }
+
+    pub fn encode_u32(&self, val: u32) -> [u8; 4] {
+        return match self.byte_order {
+            ByteOrder::Little => val.to_le_bytes(),
+            ByteOrder::Big => val.to_be_bytes()
+        };
+    }
+
+    pub fn decode_u32(&self, bytes: [u8; 4]) -> u32 {
+        return match self.byte_order {
+            ByteOrder::Little => u32::from_le_bytes(bytes),
+            ByteOrder::Big => u32::from_be_bytes(bytes)
+        };
+    }
+
+    pub fn encode_i64(&self, val: i64) -> [u8; 8] {
+        return match self.byte_order {
+            ByteOrder::Little => val.to_le_bytes(),
+            ByteOrder::Big => val.to_be_bytes()
+        };
+    }
+
+    pub fn encode_u64(&self, val: u64) -> [u8; 8] {
+        return match self.byte_order {
+            ByteOrder::Little => val.to_le_bytes(),
+            ByteOrder::Big => val.to_be_bytes()
+        };
+    }
+
+    pub fn decode_i64(&self, bytes: [u8; 8]) -> i64 {
+        return match self.byte_order {
+            ByteOrder::Little => i64::from_le_bytes(bytes),
+            ByteOrder::Big => i64::from_be_bytes(bytes)
+        };
+    }
+
+    pub fn decode_u64(&self, bytes: [u8; 8]) -> u64 {
+        return match self.byte_order {
+            ByteOrder::Little => u64::from_le_bytes(bytes),
+            ByteOrder::Big => u64::from_be_bytes(bytes)
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum PageError {
+    Io { path: PathBuf, source: io::Error },
+    UnknownFormat { path: PathBuf },
+    UnsupportedVersion { path: PathBuf, version: u8 },
+    InvalidHeader { path: PathBuf, field: &'static str, value: u8 },
+    TruncatedHeader { path: PathBuf, len: usize },
+    Mismatch { path: PathBuf, expected: PageHeader, found: PageHeader },
+    WrongType { path: PathBuf, expected: ValueType, found: ValueType },
+    Corrupt { path: PathBuf, offset: u64, expected: u32, found: u32 },
+    TruncatedRecord { path: PathBuf, offset: u64, len: usize },
+    Sealed { path: PathBuf }
+}
+
+impl PageError {
+    pub fn io(path: &PathBuf, source: io::Error) -> PageError {
+        return PageError::Io { path: path.clone(), source };
+    }
+}
+
+impl Display for PageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            PageError::Io { path, source } =>
+                write!(f, "i/o error on page {}: {}", path.display(), source),
+            PageError::UnknownFormat { path } =>
+                write!(f, "{} is not a page file (bad magic bytes)", path.display()),
+            PageError::UnsupportedVersion { path, version } =>
+                write!(f, "page {} has unsupported format version {} (expected {})",
+                       path.display(), version, FORMAT_VERSION),
+            PageError::InvalidHeader { path, field, value } =>
+                write!(f, "page {} has invalid {} {} in its header", path.display(), field, value),
+            PageError::TruncatedHeader { path, len } =>
+                write!(f, "page {} is truncated: only {} of {} header bytes present",
+                       path.display(), len, HEADER_SIZE),
+            PageError::Mismatch { path, expected, found } =>
+                write!(f, "page {} has {:?}/{:?} records, expected {:?}/{:?}",
+                       path.display(),
+                       found.timestamp_unit, found.record_layout,
+                       expected.timestamp_unit, expected.record_layout),
+            PageError::WrongType { path, expected, found } =>
+                write!(f, "page {} holds {} values, expected {}", path.display(), found, expected),
+            PageError::Corrupt { path, offset, expected, found } =>
+                write!(f, "page {} is corrupt at offset {}: checksum {:08x}, computed {:08x}",
+                       path.display(), offset, expected, found),
+            PageError::TruncatedRecord { path, offset, len } =>
+                write!(f, "page {} ends with a partial record at offset {} ({} bytes)",
+                       path.display(), offset, len),
+            PageError::Sealed { path } =>
+                write!(f, "page {} is compressed and can't be appended to", path.display())
+        };
+    }
+}
+
+impl std::error::Error for PageError {}
@@ -0,0 +1,191 @@
+use std::fmt::{Display, Formatter};
+
+// A fixed point number, mantissa * 10^-scale. Prices kept as decimals
+// add up exactly, where the same prices as floats pick up rounding error.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Decimal {
+    pub mantissa: i64,
+    pub scale: u8
+}
+
+impl Decimal {
+    pub fn new(mantissa: i64, scale: u8) -> Decimal {
+        return Decimal {
+            mantissa,
+            scale
+        };
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        return self.mantissa as f64 / 10f64.powi(self.scale as i32);
+    }
+}
+
+// Formatted from the mantissa, as going through a float would round
+// away the digits of a large one.
+impl Display for Decimal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let scale = self.scale as usize;
+
+        if scale == 0 {
+            return write!(f, "{}{}", sign, self.mantissa.unsigned_abs());
+        }
+
+        // Padded so that there is at least one digit before the point.
+        let digits = format!("{:0>width$}", self.mantissa.unsigned_abs(), width = scale + 1);
+        let (whole, fraction) = digits.split_at(digits.len() - scale);
+
+        return write!(f, "{}{}.{}", sign, whole, fraction);
+    }
+}
+
+// The type of every value in a stream. The scale of a decimal is part of
+// its type, so that values can be stored without it.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ValueType {
+    F64,
+    I64,
+    F32,
+    Bool,
+    Decimal(u8)
+}
+
+impl ValueType {
+    pub fn is_numeric(&self) -> bool {
+        return *self != ValueType::Bool;
+    }
+
+    // Stored in the page header as a type code followed by the scale,
+    // which is zero for everything but decimals. Pages written before
+    // values were typed hold f64s and have zeroes here.
+    pub fn to_bytes(&self) -> [u8; 2] {
+        return match self {
+            ValueType::F64 => [0, 0],
+            ValueType::I64 => [1, 0],
+            ValueType::F32 => [2, 0],
+            ValueType::Bool => [3, 0],
+            ValueType::Decimal(scale) => [4, *scale]
+        };
+    }
+
+    pub fn from_bytes(bytes: [u8; 2]) -> Option<ValueType> {
+        return match bytes {
+            [0, 0] => Some(ValueType::F64),
+            [1, 0] => Some(ValueType::I64),
+            [2, 0] => Some(ValueType::F32),
+            [3, 0] => Some(ValueType::Bool),
+            [4, scale] => Some(ValueType::Decimal(scale)),
+            _ => None
+        };
+    }
+
+    // Rebuilds a value from the bits written by Value::to_bits.
+    pub fn from_bits(&self, bits: u64) -> Value {
+        return match self {
+            ValueType::F64 => Value::F64(f64::from_bits(bits)),
+            ValueType::I64 => Value::I64(bits as i64),
+            ValueType::F32 => Value::F32(f32::from_bits(bits as u32)),
+            ValueType::Bool => Value::Bool(bits != 0),
+            ValueType::Decimal(scale) => Value::Decimal(Decimal::new(bits as i64, *scale))
+        };
+    }
+}
+
+impl Display for ValueType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            ValueType::F64 => write!(f, "f64"),
+            ValueType::I64 => write!(f, "i64"),
+            ValueType::F32 => write!(f, "f32"),
+            ValueType::Bool => write!(f, "bool"),
+            ValueType::Decimal(scale) => write!(f, "decimal({})", scale)
+        };
+    }
+}
+
+// Values of the same type compare as their type would. Values of
+// different types shouldn't meet, as each stream holds a single type.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+pub enum Value {
+    F64(f64),
+    I64(i64),
+    F32(f32),
+    Bool(bool),
+    Decimal(Decimal)
+}
+
+impl Value {
+    pub fn value_type(&self) -> ValueType {
+        return match self {
+            Value::F64(_) => ValueType::F64,
+            Value::I64(_) => ValueType::I64,
+            Value::F32(_) => ValueType::F32,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Decimal(v) => ValueType::Decimal(v.scale)
+        };
+    }
+
+    // Every type fits in 8 bytes, so that records stay fixed width.
+    pub fn to_bits(&self) -> u64 {
+        return match self {
+            Value::F64(v) => v.to_bits(),
+            Value::I64(v) => *v as u64,
+            Value::F32(v) => v.to_bits() as u64,
+            Value::Bool(v) => *v as u64,
+            Value::Decimal(v) => v.mantissa as u64
+        };
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        return match self {
+            Value::F64(v) => *v,
+            Value::I64(v) => *v as f64,
+            Value::F32(v) => *v as f64,
+            Value::Bool(v) => *v as u8 as f64,
+            Value::Decimal(v) => v.to_f64()
+        };
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            Value::F64(v) => write!(f, "{}", v),
+            Value::I64(v) => write!(f, "{}", v),
+            Value::F32(v) => write!(f, "{}", v),
+            Value::Bool(v) => write!(f, "{}", v),
+            Value::Decimal(v) => write!(f, "{}", v)
+        };
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Value {
+        return Value::F64(v);
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Value {
+        return Value::I64(v);
+    }
+}
+
+impl From<f32> for Value {
+    fn from(v: f32) -> Value {
+        return Value::F32(v);
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Value {
+        return Value::Bool(v);
+    }
+}
+
+impl From<Decimal> for Value {
+    fn from(v: Decimal) -> Value {
+        return Value::Decimal(v);
+    }
+}
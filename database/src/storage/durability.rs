@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use crate::storage::error::StorageError;
+
+// When a vessel forces flushed pages, and the records appended to its
+// write-ahead log, out of the page cache and onto disk.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Durability {
+    // Leave it to the OS. A crash can lose anything flushed since the
+    // OS last wrote the page cache back.
+    None,
+    // Sync every page as it is flushed, and the log on every write.
+    EveryFlush,
+    // Sync the pages flushed in the last this many milliseconds together,
+    // bounding what a crash can lose while keeping syncs off the hot path.
+    GroupCommit(u64)
+}
+
+// Tracks the files which have been written but not yet synced. Each is
+// synced through the handle it was written with, rather than reopened,
+// and one dropped by a purge or retention since is synced harmlessly.
+pub struct SyncState {
+    durability: Durability,
+    dirty: HashMap<PathBuf, File>,
+    last_sync: Instant
+}
+
+impl SyncState {
+    pub fn new(durability: Durability) -> SyncState {
+        return SyncState {
+            durability,
+            dirty: HashMap::new(),
+            last_sync: Instant::now()
+        };
+    }
+
+    // Called once records have been written to a page file or the log.
+    pub fn flushed(&mut self, path: &PathBuf, file: &File) -> Result<(), StorageError> {
+        return match self.durability {
+            Durability::None => Ok(()),
+            Durability::EveryFlush => file.sync_data().map_err(|e| StorageError::io(path, e)),
+            Durability::GroupCommit(_) => {
+                // The log is replaced by a new file when it's reset, so the
+                // handle kept is always that of the latest write.
+                let file = file.try_clone().map_err(|e| StorageError::io(path, e))?;
+                self.dirty.insert(path.clone(), file);
+                self.tick()
+            }
+        };
+    }
+
+    // Syncs the pending group, if it has been waiting long enough.
+    pub fn tick(&mut self) -> Result<(), StorageError> {
+        let interval = match self.durability {
+            Durability::GroupCommit(ms) => Duration::from_millis(ms),
+            _ => return Ok(())
+        };
+
+        if self.dirty.is_empty() || self.last_sync.elapsed() < interval {
+            return Ok(());
+        }
+
+        return self.sync();
+    }
+
+    pub fn sync(&mut self) -> Result<(), StorageError> {
+        for (path, file) in &self.dirty {
+            file.sync_data().map_err(|e| StorageError::io(path, e))?;
+        }
+
+        self.dirty.clear();
+        self.last_sync = Instant::now();
+        return Ok(());
+    }
+}
+
+// A new file isn't durable until the directory entry pointing at it is.
+pub fn sync_dir(path: &PathBuf) -> Result<(), StorageError> {
+    let dir = File::open(path).map_err(|e| StorageError::io(path, e))?;
+    return dir.sync_all().map_err(|e| StorageError::io(path, e));
+}
@@ -0,0 +1,87 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use crate::storage::domain::bucket::Bucketing;
+use crate::storage::domain::page_header::PageError;
+use crate::storage::domain::value::ValueType;
+use crate::storage::late_data::LateDataError;
+
+// Everything the public storage APIs can fail with. Page level problems
+// keep their own type, so that callers can tell corruption apart from a
+// failing disk or a rejected write.
+#[derive(Debug)]
+pub enum StorageError {
+    Io {
+        path: PathBuf,
+        source: std::io::Error
+    },
+    Page(PageError),
+    LateData(LateDataError),
+    // A value, or a stream wired in as an input, of the wrong type.
+    TypeMismatch {
+        path: PathBuf,
+        expected: ValueType,
+        found: ValueType
+    },
+    // A stream opened with a definition that doesn't match the one its
+    // data was written under. One line per differing setting.
+    MetadataMismatch {
+        path: PathBuf,
+        diff: Vec<String>
+    },
+    InvalidMetadata {
+        path: PathBuf
+    },
+    // A page which doesn't start a bucket, so was written with different bucketing.
+    Misaligned {
+        path: PathBuf,
+        bucketing: Bucketing
+    },
+    // A stream another process, or another vessel in this one, has open.
+    Locked {
+        path: PathBuf,
+        pid: Option<u32>
+    }
+}
+
+impl StorageError {
+    pub fn io(path: &PathBuf, source: std::io::Error) -> StorageError {
+        return StorageError::Io { path: path.clone(), source };
+    }
+}
+
+impl From<PageError> for StorageError {
+    fn from(e: PageError) -> StorageError {
+        return StorageError::Page(e);
+    }
+}
+
+impl From<LateDataError> for StorageError {
+    fn from(e: LateDataError) -> StorageError {
+        return StorageError::LateData(e);
+    }
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        return match self {
+            StorageError::Io { path, source } =>
+                write!(f, "i/o error on {}: {}", path.display(), source),
+            StorageError::Page(e) => write!(f, "{}", e),
+            StorageError::LateData(e) => write!(f, "{}", e),
+            StorageError::TypeMismatch { path, expected, found } =>
+                write!(f, "{} holds {} values, but was given {}", path.display(), expected, found),
+            StorageError::MetadataMismatch { path, diff } =>
+                write!(f, "{} doesn't match its stream definition:\n  {}", path.display(), diff.join("\n  ")),
+            StorageError::InvalidMetadata { path } =>
+                write!(f, "{} can't be read as stream metadata", path.display()),
+            StorageError::Misaligned { path, bucketing } =>
+                write!(f, "{} doesn't start a page under {} bucketing", path.display(), bucketing),
+            StorageError::Locked { path, pid: Some(pid) } =>
+                write!(f, "{} is already opened by pid {}", path.display(), pid),
+            StorageError::Locked { path, pid: None } =>
+                write!(f, "{} is already opened by another process", path.display())
+        };
+    }
+}
+
+impl std::error::Error for StorageError {}
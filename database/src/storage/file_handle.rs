@@ -1,22 +1,103 @@
 use std::collections::HashMap;
 use std::fs;
 use std::fs::{DirEntry, File, OpenOptions};
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, ErrorKind};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, RwLock};
+use crate::storage::descriptors::DescriptorCache;
 use crate::storage::domain::bucket::Bucket;
+use crate::storage::domain::mapped_page::MappedPage;
+use crate::storage::domain::page_header::{PageError, PageHeader};
+use crate::storage::domain::value::ValueType;
 use crate::storage::file_system::FileSystem;
 
 pub struct FileHandle {
     pub path: PathBuf,
-    pub bucket: Bucket
+    pub bucket: Bucket,
+    pub value_type: ValueType,
+    // The page being written to is kept open between flushes, along with
+    // its header once it's known.
+    writer: Option<File>,
+    header: Option<PageHeader>,
+    reads: Arc<DescriptorCache>
 }
 
 impl FileHandle {
-    pub fn new(path: PathBuf, bucket: Bucket) -> FileHandle {
+    pub fn new(path: PathBuf, bucket: Bucket, value_type: ValueType, reads: Arc<DescriptorCache>) -> FileHandle {
         return FileHandle {
             path,
-            bucket
+            bucket,
+            value_type,
+            writer: None,
+            header: None,
+            reads
         }
     }
-}
\ No newline at end of file
+
+    pub fn writer(&self) -> Option<&File> {
+        return self.writer.as_ref();
+    }
+
+    pub fn header(&self) -> Option<PageHeader> {
+        return self.header;
+    }
+
+    pub fn set_writer(&mut self, file: File) {
+        self.writer = Some(file);
+        self.header = None;
+    }
+
+    pub fn set_header(&mut self, header: PageHeader) {
+        self.header = Some(header);
+    }
+
+    // Called once the page has been turned, and won't be written again.
+    pub fn close_writer(&mut self) {
+        self.writer = None;
+        self.header = None;
+    }
+
+    // A file to read the page through, or None if it hasn't been created.
+    // It should be released once read, unless the read failed.
+    pub fn open_read(&self) -> Result<Option<File>, PageError> {
+        if let Some(file) = self.reads.take(&self.path) {
+            return Ok(Some(file));
+        }
+
+        return match File::open(&self.path) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(PageError::io(&self.path, e))
+        };
+    }
+
+    pub fn release(&self, file: File) {
+        self.reads.put(&self.path, file);
+    }
+
+    // The page mapped into memory, or None if it hasn't been written to.
+    // Only sealed pages can be mapped, as a mapping doesn't grow with the
+    // file and would miss anything appended after it was made.
+    pub fn mapped(&self) -> Result<Option<Arc<MappedPage>>, PageError> {
+        if let Some(page) = self.reads.mapped(&self.path) {
+            return Ok(Some(page));
+        }
+
+        let page = MappedPage::open(&self.path)?.map(Arc::new);
+
+        // A compressed page is decoded whole when it's mapped, so keeping
+        // it would keep every record of it in memory.
+        if let Some(page) = page.as_ref().filter(|v| !v.is_compressed()) {
+            self.reads.put_mapped(&self.path, page.clone());
+        }
+
+        return Ok(page);
+    }
+
+    // Closes every file open on the page, which has been replaced or
+    // removed, so that nothing carries on using what was there before.
+    pub fn replaced(&mut self) {
+        self.close_writer();
+        self.reads.forget(&self.path);
+    }
+}
@@ -1,124 +1,487 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::Error;
 use std::fs;
-use std::fs::{DirEntry, File};
+use std::fs::{DirEntry, File, OpenOptions};
+use std::io::ErrorKind;
 use std::ops::Index;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread::current;
 use chrono::{DateTime, Utc};
+use log::warn;
 use crate::Blob;
 use crate::domain::UnixTime;
-use crate::storage::domain::bucket::Bucket;
-use crate::storage::domain::data_page::DataPage;
+use crate::storage::descriptors::DescriptorCache;
+use crate::storage::domain::bucket::{Bucket, Bucketing};
+use crate::storage::domain::data_page::{DataPage, TEMP_SUFFIX};
+use crate::storage::domain::page_header::{PageError, PageHeader};
+use crate::storage::domain::value::ValueType;
+use crate::storage::durability::{Durability, SyncState, sync_dir};
+use crate::storage::error::StorageError;
 use crate::storage::file_handle::FileHandle;
+use crate::storage::layout::{Layout, migrate, parse_shard};
+use crate::storage::lock::{LockMode, VesselLock};
+use crate::storage::page_index::PageIndex;
+use crate::storage::reader::VesselReader;
+use crate::storage::recovery::{QUARANTINE_SUFFIX, TornTail, TornTailPolicy};
+use crate::storage::retention::Retention;
+use crate::storage::tombstones::{TOMBSTONE_FILE_NAME, Tombstones};
+use crate::storage::write_ahead_log::WAL_FILE_NAME;
+
+// Written alongside the pages by whatever owns the stream.
+pub const METADATA_FILE_NAME: &str = "stream.meta";
 
 pub struct FileSystem {
     path: PathBuf,
-    files: BTreeMap<Bucket, Arc<RwLock<FileHandle>>>
+    files: Arc<PageIndex>,
+    tombstones: Arc<RwLock<Tombstones>>,
+    // The buffer of whichever page is being written to.
+    pending: Arc<RwLock<Vec<Blob>>>,
+    // The records held back by the vessel's reorder buffer, if it has one.
+    held: Arc<RwLock<BTreeMap<UnixTime, Blob>>>,
+    descriptors: Arc<DescriptorCache>,
+    bucketing: Bucketing,
+    layout: Layout,
+    durability: Durability,
+    value_type: ValueType,
+    lock: Arc<VesselLock>,
+    // Every sealed page up to this one has been compacted, so compaction
+    // carries on after it rather than looking at every page again.
+    compacted: Option<Bucket>
 }
 
 impl FileSystem {
-    pub fn new(path: PathBuf, page_length: i64) -> (FileSystem, Option<DataPage>) {
-        fs::create_dir_all(path.clone());
-        // Read the file system to build up an in-memory index of the
-        // current file system. It doesn't matter that this is slow,
-        // because this only happens on initialization.
-        let mut paths = fs::read_dir(&path)
-            .unwrap()
-            .collect::<Vec<Result<DirEntry, std::io::Error>>>()
-            .into_iter()
-            .map(|v| v.unwrap())
-            .map(|v| (v.file_name().into_string().unwrap().parse::<UnixTime>().unwrap(), v))
-            .collect::<Vec<(UnixTime, DirEntry)>>();
+    pub fn new(
+        path: PathBuf,
+        bucketing: Bucketing,
+        durability: Durability,
+        torn_tail: TornTailPolicy,
+        value_type: ValueType,
+        layout: Layout,
+        lock: VesselLock,
+        descriptors: Arc<DescriptorCache>) -> Result<(FileSystem, Option<DataPage>), StorageError> {
 
-        paths.sort_by(|(a,_),(b,_)| a.cmp(b));
+        // Readers leave the directory as they find it, so only the writer
+        // migrates pages and repairs torn ones.
+        if lock.mode() == LockMode::Exclusive {
+            fs::create_dir_all(path.clone()).map_err(|e| StorageError::io(&path, e))?;
 
-        let mut files = BTreeMap::new();
-        let mut last: Option<Arc<RwLock<FileHandle>>> = None;
+            // Pages left in another layout are moved before anything is read,
+            // which is how a flat stream is migrated to the sharded layout.
+            migrate(&path, layout, durability)?;
+        }
 
-        for (date, entry) in paths {
-            let bucket = Bucket::new(date, page_length);
-            let node = FileHandle::new(entry.path(), bucket);
-            let arc = Arc::new(RwLock::new(node));
+        let repair = match lock.mode() {
+            LockMode::Exclusive => Some(torn_tail),
+            LockMode::Shared => None
+        };
 
-            files.insert(bucket, arc.clone());
-            last = Some(arc.clone());
-        }
+        // Build up an in-memory index of the pages. A flat stream lists
+        // every page now, a sharded one lists each shard as it's needed.
+        let files = Arc::new(PageIndex::open(&path, layout, bucketing, durability, repair, value_type, descriptors.clone())?);
+        let tombstones = Arc::new(RwLock::new(Tombstones::open(&path)?));
 
         let mut file_system = FileSystem {
             path,
-            files
+            files,
+            tombstones,
+            pending: Arc::new(RwLock::new(Vec::new())),
+            held: Arc::new(RwLock::new(BTreeMap::new())),
+            descriptors,
+            bucketing,
+            layout,
+            durability,
+            value_type,
+            lock: Arc::new(lock),
+            compacted: None
+        };
+
+        let page = match file_system.files.last()? {
+            Some(bucket) => Some(file_system.create_page(bucket)?),
+            None => None
         };
 
-        let page = last.map(|v| {
-            let guard = v.write().unwrap();
-            return file_system.create_page(guard.bucket);
-        });
+        return Ok((file_system, page));
+    }
+
+    // The page files of a stream in either layout, in time order, along
+    // with the start of the bucket each one holds.
+    pub fn list_pages(path: &PathBuf) -> Result<Vec<(UnixTime, PathBuf)>, StorageError> {
+        let mut paths = Self::list_dir(path)?;
+
+        for (_, shard) in Self::list_shards(path)? {
+            paths.extend(Self::list_dir(&shard)?);
+        }
+
+        paths.sort_by(|(a,_),(b,_)| a.cmp(b));
 
-        return (file_system, page);
+        return Ok(paths);
     }
 
-    pub fn read(&self, bucket: Bucket) -> Vec<Blob> {
-        let file_handle = self.files.get(&bucket);
+    // The page files directly in a directory, in time order.
+    pub fn list_dir(path: &PathBuf) -> Result<Vec<(UnixTime, PathBuf)>, StorageError> {
+        let mut paths = Vec::new();
+
+        for entry in fs::read_dir(path).map_err(|e| StorageError::io(path, e))? {
+            let entry = entry.map_err(|e| StorageError::io(path, e))?;
+
+            if !Self::is_page(&entry) {
+                continue;
+            }
 
-        if let Some(v) = file_handle {
-            let page = DataPage::open_page(bucket.clone(),  v.clone());
-            return page.read();
+            match Self::parse_name(&entry) {
+                Some(date) => paths.push((date, entry.path())),
+                None => warn!("Skipping {}, which isn't a page file", entry.path().display())
+            }
         }
 
-        return vec![];
+        paths.sort_by(|(a,_),(b,_)| a.cmp(b));
+
+        return Ok(paths);
     }
 
-    pub fn get_last_time(&self) -> UnixTime {
-        let last = self.files.last_key_value();
+    // The `YYYY/MM` shard directories of a stream, by the start of their month.
+    pub fn list_shards(path: &PathBuf) -> Result<Vec<(UnixTime, PathBuf)>, StorageError> {
+        let mut shards = Vec::new();
 
-        return match last {
-            Some((bucket, file)) => {
-                let page = DataPage::open_page(bucket.clone(), file.clone());
+        for year in Self::sub_dirs(path)? {
+            for month in Self::sub_dirs(&year)? {
+                let name = |v: &PathBuf| v.file_name().unwrap().to_string_lossy().to_string();
 
-                let records = page.read();
-                if records.len() == 0 {
-                    return 0;
+                if let Some(start) = parse_shard(&name(&year), &name(&month)) {
+                    shards.push((start, month));
                 }
+            }
+        }
+
+        shards.sort_by(|(a,_),(b,_)| a.cmp(b));
+
+        return Ok(shards);
+    }
 
-                let last = &records[records.len()-1];
+    fn sub_dirs(path: &PathBuf) -> Result<Vec<PathBuf>, StorageError> {
+        let mut dirs = Vec::new();
 
-                return last.timestamp;
+        for entry in fs::read_dir(path).map_err(|e| StorageError::io(path, e))? {
+            let entry = entry.map_err(|e| StorageError::io(path, e))?;
+            let file_type = entry.file_type().map_err(|e| StorageError::io(&entry.path(), e))?;
+
+            if file_type.is_dir() {
+                dirs.push(entry.path());
             }
-            None => 0
         }
+
+        return Ok(dirs);
     }
 
-    pub fn create_page(&mut self, bucket: Bucket) -> DataPage {
-        let file = self.files.get(&bucket);
+    // The write-ahead log, tombstones, any temporary file left behind by an
+    // interrupted page rewrite and quarantined records live alongside the
+    // pages, as do the shard directories of the sharded layout.
+    fn is_page(entry: &DirEntry) -> bool {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
 
-        if let Some(v) = file {
-            return DataPage::open_page(bucket, v.clone());
+        if entry.file_type().map_or(true, |v| v.is_dir()) {
+            return false;
+        }
+
+        return name != WAL_FILE_NAME
+            && name != TOMBSTONE_FILE_NAME
+            && name != METADATA_FILE_NAME
+            && !name.ends_with(TEMP_SUFFIX)
+            && !name.ends_with(QUARANTINE_SUFFIX);
+    }
+
+    // Pages are named after the start of their bucket.
+    fn parse_name(entry: &DirEntry) -> Option<UnixTime> {
+        let name = entry.file_name().into_string().ok()?;
+        return name.parse::<UnixTime>().ok();
+    }
+
+    // Rejects anything in the stream directory that isn't a page file
+    // this build knows how to decode.
+    pub fn check_header(path: &PathBuf) -> Result<Option<PageHeader>, PageError> {
+        let mut file = File::open(path).map_err(|e| PageError::io(path, e))?;
+        return PageHeader::read_from(path, &mut file);
+    }
+
+    // Partial records cut from the end of pages when they were listed.
+    pub fn torn(&self) -> Vec<TornTail> {
+        return self.files.torn();
+    }
+
+    pub fn lock(&self) -> &Arc<VesselLock> {
+        return &self.lock;
+    }
+
+    // A reader over the same pages, which can be sent to other threads.
+    pub fn reader(&self) -> VesselReader {
+        return VesselReader::new(
+            self.path.clone(),
+            self.files.clone(),
+            self.tombstones.clone(),
+            self.pending.clone(),
+            self.held.clone(),
+            self.bucketing);
+    }
+
+    pub fn held(&self) -> Arc<RwLock<BTreeMap<UnixTime, Blob>>> {
+        return self.held.clone();
+    }
+
+    pub fn page_count(&self) -> Result<usize, StorageError> {
+        return self.files.len();
+    }
+
+    pub fn first_bucket(&self) -> Result<Option<Bucket>, StorageError> {
+        return self.files.first();
+    }
+
+    pub fn last_bucket(&self) -> Result<Option<Bucket>, StorageError> {
+        return self.files.last();
+    }
+
+    pub fn prev_bucket(&self, bucket: Bucket) -> Result<Option<Bucket>, StorageError> {
+        return self.files.prev(bucket);
+    }
+
+    // The first bucket after the given one which has a page, skipping
+    // over any periods where nothing was written.
+    pub fn next_bucket(&self, bucket: Bucket) -> Result<Option<Bucket>, StorageError> {
+        return self.files.next(bucket);
+    }
+
+    pub fn get_last_time(&self) -> Result<Option<UnixTime>, StorageError> {
+        return self.files.last_time();
+    }
+
+    // Compresses every sealed page not yet compacted. The last page is the
+    // one being written to, as pages are only ever turned forwards.
+    pub fn compact(&mut self) -> Result<usize, StorageError> {
+        let mut compacted = 0;
+        let last = self.files.last()?;
+
+        let mut bucket = match self.compacted {
+            Some(v) => self.files.next(v)?,
+            None => self.files.first()?
+        };
+
+        while let Some(v) = bucket.filter(|v| Some(*v) < last) {
+            let page = DataPage::open_page(v, self.files.get(v)?.unwrap());
+
+            if page.compact()? {
+                compacted += 1;
+            }
+
+            self.compacted = Some(v);
+            bucket = self.files.next(v)?;
         }
 
-        let file = self.create(bucket);
-        return DataPage::open_page(bucket, file)
+        return Ok(compacted);
     }
 
-    pub fn turn_page(&mut self, page: DataPage, bucket: Bucket) -> DataPage {
-        let file = self.files.get(&bucket);
+    // Rewritten pages are uncompressed again, so compaction has to go
+    // back for them.
+    pub fn rewritten(&mut self, bucket: Bucket) -> Result<(), StorageError> {
+        if self.compacted.map_or(false, |v| v >= bucket) {
+            self.compacted = self.files.prev(bucket)?;
+        }
+
+        return Ok(());
+    }
+
+    pub fn delete_range(&mut self, from: UnixTime, to: UnixTime) -> Result<(), StorageError> {
+        return Ok(self.tombstones.write().unwrap().add(from, to)?);
+    }
+
+    // Rewrites every page holding deleted records without them, removing
+    // any page left empty, and then drops the tombstones. The last page is
+    // the one being written to, so is rewritten even if empty.
+    pub fn purge(&mut self) -> Result<usize, StorageError> {
+        // Only this thread changes the tombstones, so they can be copied
+        // out and readers left to filter against them until the end.
+        let tombstones = self.tombstones.read().unwrap().ranges().to_vec();
+
+        if tombstones.is_empty() {
+            return Ok(0);
+        }
+
+        let last = self.last_bucket()?;
+        let mut affected = BTreeSet::new();
+
+        for (from, to) in &tombstones {
+            let first = Bucket::for_time(*from, self.bucketing);
+
+            let mut bucket = match self.files.get(first)? {
+                Some(_) => Some(first),
+                None => self.files.next(first)?
+            };
+
+            while let Some(v) = bucket.filter(|v| v.val < *to) {
+                affected.insert(v);
+                bucket = self.files.next(v)?;
+            }
+        }
+
+        let mut purged = 0;
+
+        for bucket in affected {
+            let page = DataPage::open_page(bucket, self.files.get(bucket)?.unwrap());
+
+            let mut records = page.read()?;
+            let count = records.len();
+
+            self.tombstones.read().unwrap().retain_live(&mut records);
+
+            if records.len() == count {
+                continue;
+            }
+
+            if records.is_empty() && Some(bucket) != last {
+                self.remove(bucket)?;
+            } else {
+                page.rewrite(&records)?;
+                self.rewritten(bucket)?;
+            }
+
+            purged += 1;
+        }
+
+        self.tombstones.write().unwrap().clear()?;
+        return Ok(purged);
+    }
+
+    // Drops every page which the retention policy has expired, returning
+    // the paths of the files removed. The last page is always kept, as it
+    // is the one being written to.
+    pub fn expire(&mut self, retention: Retention, now: UnixTime) -> Result<Vec<PathBuf>, StorageError> {
+        let expired = match retention {
+            Retention::Forever => vec![],
+            // Only the oldest pages are looked at, so that a sharded stream
+            // doesn't list every shard to find the few which have expired.
+            Retention::MaxAge(age) => {
+                let mut expired = Vec::new();
+                let mut bucket = self.files.first()?;
+
+                while let Some(v) = bucket.filter(|v| v.next().val <= now.saturating_sub(age)) {
+                    bucket = self.files.next(v)?;
+
+                    if bucket.is_some() {
+                        expired.push(v);
+                    }
+                }
+
+                expired
+            }
+            // The pages kept are counted back from the last, and everything
+            // before them walked from the first, so that only the shards at
+            // either end are listed.
+            Retention::MaxPages(count) => {
+                let mut expired = Vec::new();
+                let mut oldest_kept = self.files.last()?;
+
+                for _ in 1..count.max(1) {
+                    oldest_kept = match oldest_kept {
+                        Some(v) => self.files.prev(v)?,
+                        None => break
+                    };
+                }
+
+                if let Some(oldest_kept) = oldest_kept {
+                    let mut bucket = self.files.first()?;
+
+                    while let Some(v) = bucket.filter(|v| *v < oldest_kept) {
+                        expired.push(v);
+                        bucket = self.files.next(v)?;
+                    }
+                }
+
+                expired
+            }
+        };
+
+        let mut removed = Vec::with_capacity(expired.len());
+
+        for bucket in expired {
+            if let Some(path) = self.remove(bucket)? {
+                removed.push(path);
+            }
+        }
+
+        return Ok(removed);
+    }
+
+    fn remove(&mut self, bucket: Bucket) -> Result<Option<PathBuf>, StorageError> {
+        if let Some(file) = self.files.remove(bucket)? {
+            let mut handle = file.write().unwrap();
+            handle.replaced();
+
+            match fs::remove_file(&handle.path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(StorageError::io(&handle.path, e)),
+                _ => ()
+            }
+
+            return Ok(Some(handle.path.clone()));
+        }
+
+        return Ok(None);
+    }
+
+    // Pages are written through the one buffer, which readers merge in.
+    // Turning the page moves the buffer on to the next.
+    pub fn create_page(&mut self, bucket: Bucket) -> Result<DataPage, StorageError> {
+        let file = match self.files.get(bucket)? {
+            Some(v) => v,
+            None => self.create(bucket)?
+        };
+
+        return Ok(DataPage::with_buffer(bucket, file, self.pending.clone()));
+    }
+
+    pub fn turn_page(&mut self, page: DataPage, bucket: Bucket, sync: &mut SyncState) -> Result<DataPage, StorageError> {
+        let file = self.files.get(bucket)?;
 
         if let Some(v) = file {
-            return page.update(bucket, v.clone());
+            return page.update(bucket, v, sync);
         }
 
-        let file = self.create(bucket);
-        return page.update(bucket, file)
+        let file = self.create(bucket)?;
+        return page.update(bucket, file, sync)
     }
 
-    fn create(&mut self, bucket: Bucket) -> Arc<RwLock<FileHandle>> {
-        let file_handle = FileHandle::new(
-            self.path.clone().join(bucket.val.to_string()), bucket);
+    fn create(&mut self, bucket: Bucket) -> Result<Arc<RwLock<FileHandle>>, StorageError> {
+        let path = self.layout.page_path(&self.path, bucket.val);
+        let dir = path.parent().unwrap().to_path_buf();
+        let new_shard = !dir.exists();
+
+        if new_shard {
+            fs::create_dir_all(&dir).map_err(|e| StorageError::io(&dir, e))?;
+        }
+
+        // Kept open as the page's writer, as it's about to be written to.
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| StorageError::io(&path, e))?;
+
+        if self.durability != Durability::None {
+            sync_dir(&dir)?;
+
+            // The shard directory has to outlast a crash as well as the page.
+            if new_shard {
+                sync_dir(&dir.parent().unwrap().to_path_buf())?;
+                sync_dir(&self.path)?;
+            }
+        }
+
+        let mut file_handle = FileHandle::new(path, bucket, self.value_type, self.descriptors.clone());
+        file_handle.set_writer(file);
 
         let arc = Arc::new(RwLock::new(file_handle));
         self.files.insert(bucket, arc.clone());
 
-        return arc;
+        return Ok(arc);
     }
 }
\ No newline at end of file
@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::domain::UnixTime;
+use crate::storage::domain::blob::Blob;
+
+// What a vessel does with a record which is no later than the last one
+// it has accepted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LatePolicy {
+    // Drop the record and report it back to the caller.
+    Reject,
+    // Hold records back for the given number of milliseconds so that
+    // anything arriving out of order within the window can be sorted.
+    Reorder(i64),
+    // Insert the record into the page it belongs to, rewriting the page.
+    Rewrite
+}
+
+#[derive(Clone, Default)]
+pub struct LateDataCounters {
+    rejected: Arc<AtomicU64>,
+    reordered: Arc<AtomicU64>,
+    rewritten: Arc<AtomicU64>
+}
+
+impl LateDataCounters {
+    pub fn new() -> LateDataCounters {
+        return LateDataCounters::default();
+    }
+
+    pub fn rejected(&self) -> u64 {
+        return self.rejected.load(Ordering::Relaxed);
+    }
+
+    pub fn reordered(&self) -> u64 {
+        return self.reordered.load(Ordering::Relaxed);
+    }
+
+    pub fn rewritten(&self) -> u64 {
+        return self.rewritten.load(Ordering::Relaxed);
+    }
+
+    pub fn add_rejected(&self, count: usize) {
+        self.rejected.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_reordered(&self, count: usize) {
+        self.reordered.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_rewritten(&self, count: usize) {
+        self.rewritten.fetch_add(count as u64, Ordering::Relaxed);
+    }
+}
+
+// The held records are shared with the vessel's readers, which read them
+// along with the write buffer.
+pub struct ReorderBuffer {
+    window: i64,
+    records: Arc<RwLock<BTreeMap<UnixTime, Blob>>>,
+    max_seen: UnixTime
+}
+
+impl ReorderBuffer {
+    pub fn new(window: i64, records: Arc<RwLock<BTreeMap<UnixTime, Blob>>>) -> ReorderBuffer {
+        return ReorderBuffer {
+            window,
+            records,
+            max_seen: UnixTime::MIN
+        };
+    }
+
+    // Returns false if a record with the same timestamp is already held.
+    pub fn insert(&mut self, record: Blob) -> bool {
+        let mut records = self.records.write().unwrap();
+
+        if records.contains_key(&record.timestamp) {
+            return false;
+        }
+
+        self.max_seen = self.max_seen.max(record.timestamp);
+        records.insert(record.timestamp, record);
+
+        return true;
+    }
+
+    // Inserts a record, replacing any held with the same timestamp.
+    pub fn upsert(&mut self, record: Blob) {
+        self.max_seen = self.max_seen.max(record.timestamp);
+        self.records.write().unwrap().insert(record.timestamp, record);
+    }
+
+    // Every record which is older than the lateness window and so can no
+    // longer have anything sorted before it, in timestamp order. They stay
+    // held until released, so that readers don't lose sight of them
+    // before they reach the write buffer.
+    pub fn ready(&self) -> Vec<Blob> {
+        let watermark = self.max_seen.saturating_sub(self.window);
+
+        return self.records
+            .read()
+            .unwrap()
+            .range(..=watermark)
+            .map(|(_, v)| *v)
+            .collect();
+    }
+
+    pub fn release(&mut self, records: &[Blob]) {
+        let mut held = self.records.write().unwrap();
+
+        for record in records {
+            held.remove(&record.timestamp);
+        }
+    }
+
+    // Drops the held records in [from, to), returning how many there were.
+    pub fn remove_range(&mut self, from: UnixTime, to: UnixTime) -> usize {
+        let mut records = self.records.write().unwrap();
+        let before = records.len();
+        records.retain(|timestamp, _| *timestamp < from || *timestamp >= to);
+
+        return before - records.len();
+    }
+
+    pub fn max_seen(&self) -> UnixTime {
+        return self.max_seen;
+    }
+
+    pub fn pending(&self) -> Vec<Blob> {
+        return self.records.read().unwrap().values().cloned().collect();
+    }
+}
+
+#[derive(Debug)]
+pub struct LateDataError {
+    pub path: PathBuf,
+    // None if nothing had been stored yet.
+    pub last: Option<UnixTime>,
+    pub rejected: Vec<Blob>,
+    // The rest of the batch, which was stored and still goes downstream.
+    pub accepted: Vec<Blob>
+}
+
+impl Display for LateDataError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} rejected {} late records", self.path.display(), self.rejected.len())?;
+
+        if let Some(first) = self.rejected.first() {
+            write!(f, ", the first at {}", first.timestamp)?;
+        }
+
+        return match self.last {
+            Some(last) => write!(f, " (last accepted {})", last),
+            None => write!(f, " (nothing accepted yet)")
+        };
+    }
+}
+
+impl std::error::Error for LateDataError {}
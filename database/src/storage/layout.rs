@@ -0,0 +1,116 @@
+use std::fs;
+use std::path::PathBuf;
+use chrono::NaiveDate;
+use log::info;
+use crate::domain::UnixTime;
+use crate::storage::domain::bucket::{Bucketing, to_utc};
+use crate::storage::durability::{Durability, sync_dir};
+use crate::storage::error::StorageError;
+use crate::storage::file_system::FileSystem;
+
+// Pages are sharded by the month their bucket starts in.
+pub const SHARDING: Bucketing = Bucketing::Monthly;
+
+// Where a stream keeps its page files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Layout {
+    // Every page directly in the stream's directory.
+    Flat,
+    // Pages in a directory per month, as `YYYY/MM/<bucket>`, so that no
+    // directory grows too large and pages are only listed when needed.
+    Sharded
+}
+
+impl Layout {
+    pub fn page_path(&self, dir: &PathBuf, bucket: UnixTime) -> PathBuf {
+        return match self {
+            Layout::Flat => dir.join(bucket.to_string()),
+            Layout::Sharded => shard_dir(dir, SHARDING.start(bucket)).join(bucket.to_string())
+        };
+    }
+}
+
+pub fn shard_dir(dir: &PathBuf, shard: UnixTime) -> PathBuf {
+    let start = to_utc(shard);
+    return dir.join(start.format("%Y").to_string()).join(start.format("%m").to_string());
+}
+
+// The start of the month a shard directory holds, if the names are those
+// of a year and a month.
+pub fn parse_shard(year: &str, month: &str) -> Option<UnixTime> {
+    if year.len() != 4 || month.len() != 2 {
+        return None;
+    }
+
+    let year = year.parse::<i32>().ok()?;
+    let month = month.parse::<u32>().ok()?;
+
+    let start = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+    return Some(start.timestamp_millis());
+}
+
+// Moves every page which isn't where the layout puts it, returning how
+// many were moved. Each page is moved by a single rename, so a migration
+// interrupted by a crash carries on from where it stopped next time.
+pub fn migrate(dir: &PathBuf, layout: Layout, durability: Durability) -> Result<usize, StorageError> {
+    let mut moved = Vec::new();
+
+    let misplaced = match layout {
+        Layout::Flat => {
+            let mut pages = Vec::new();
+
+            for (_, shard) in FileSystem::list_shards(dir)? {
+                pages.extend(FileSystem::list_dir(&shard)?);
+            }
+
+            pages
+        }
+        Layout::Sharded => FileSystem::list_dir(dir)?
+    };
+
+    for (bucket, from) in misplaced {
+        let to = layout.page_path(dir, bucket);
+        let parent = to.parent().unwrap().to_path_buf();
+
+        fs::create_dir_all(&parent).map_err(|e| StorageError::io(&parent, e))?;
+        fs::rename(&from, &to).map_err(|e| StorageError::io(&from, e))?;
+
+        moved.push((from, to));
+    }
+
+    if moved.is_empty() {
+        return Ok(0);
+    }
+
+    if layout == Layout::Flat {
+        // Whatever is left in a shard, such as quarantined records, stops
+        // its directory being removed.
+        for (_, shard) in FileSystem::list_shards(dir)? {
+            let _ = fs::remove_dir(&shard);
+            let _ = fs::remove_dir(shard.parent().unwrap());
+        }
+    }
+
+    if durability != Durability::None {
+        let mut dirs = moved
+            .iter()
+            .flat_map(|(from, to)| [from.parent(), to.parent(), to.parent().and_then(|v| v.parent())])
+            .flatten()
+            .map(|v| v.to_path_buf())
+            .filter(|v| v.exists())
+            .collect::<Vec<PathBuf>>();
+
+        dirs.sort();
+        dirs.dedup();
+
+        for dir in dirs {
+            sync_dir(&dir)?;
+        }
+
+        sync_dir(dir)?;
+    }
+
+    info!("Moved {} pages of {} into the {:?} layout", moved.len(), dir.display(), layout);
+
+    return Ok(moved.len());
+}
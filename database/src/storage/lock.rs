@@ -0,0 +1,157 @@
+use std::fs;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use crate::storage::error::StorageError;
+
+pub const LOCK_SUFFIX: &str = ".lock";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LockMode {
+    // Held by the one process writing to a stream.
+    Exclusive,
+    // Held by any number of processes reading a stream, while nothing writes to it.
+    Shared
+}
+
+// An advisory lock on a stream directory, held until dropped. The holder
+// of an exclusive lock writes its pid into the lock file, so that anyone
+// turned away can be told who has the stream open.
+//
+// The lock file sits beside the directory rather than in it, so that it
+// can be taken before anything in the directory is looked at, even if a
+// repage has left it missing, and holds while a repage swaps it out.
+pub struct VesselLock {
+    path: PathBuf,
+    // Closing the file releases the lock.
+    file: File,
+    mode: LockMode
+}
+
+impl VesselLock {
+    pub fn acquire(dir: &PathBuf, mode: LockMode) -> Result<VesselLock, StorageError> {
+        let path = lock_path(dir);
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| StorageError::io(&path, e))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(|e| StorageError::io(&path, e))?;
+
+        let locked = match mode {
+            LockMode::Exclusive => file.try_lock(),
+            LockMode::Shared => file.try_lock_shared()
+        };
+
+        match locked {
+            Ok(()) => (),
+            Err(TryLockError::WouldBlock) => return Err(StorageError::Locked {
+                path: dir.clone(),
+                pid: Self::writer(&mut file)
+            }),
+            Err(TryLockError::Error(e)) => return Err(StorageError::io(&path, e))
+        }
+
+        if mode == LockMode::Exclusive {
+            file.set_len(0).map_err(|e| StorageError::io(&path, e))?;
+            file.seek(SeekFrom::Start(0)).map_err(|e| StorageError::io(&path, e))?;
+            writeln!(file, "{}", std::process::id()).map_err(|e| StorageError::io(&path, e))?;
+        }
+
+        return Ok(VesselLock {
+            path,
+            file,
+            mode
+        });
+    }
+
+    // The pid of the process writing to the stream, if it's a writer
+    // rather than readers that holds the lock. Readers leave the pid of
+    // the last writer in the file, so it's only trusted when a shared
+    // lock can't be taken either.
+    fn writer(file: &mut File) -> Option<u32> {
+        if file.try_lock_shared().is_ok() {
+            let _ = file.unlock();
+            return None;
+        }
+
+        let mut text = String::new();
+
+        file.seek(SeekFrom::Start(0)).ok()?;
+        file.read_to_string(&mut text).ok()?;
+
+        return text.trim().parse().ok();
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        return &self.path;
+    }
+
+    pub fn mode(&self) -> LockMode {
+        return self.mode;
+    }
+}
+
+pub fn lock_path(dir: &PathBuf) -> PathBuf {
+    let mut name = dir.file_name().unwrap().to_os_string();
+    name.push(LOCK_SUFFIX);
+
+    return dir.with_file_name(name);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use crate::storage::descriptors::DescriptorCache;
+    use crate::storage::domain::bucket::Bucketing;
+    use crate::storage::domain::value::ValueType;
+    use crate::storage::durability::Durability;
+    use crate::storage::error::StorageError;
+    use crate::storage::late_data::LatePolicy;
+    use crate::storage::layout::Layout;
+    use crate::storage::recovery::TornTailPolicy;
+    use crate::storage::retention::Retention;
+    use crate::storage::testing::TempDir;
+    use crate::storage::vessel2::Vessel;
+
+    fn open(path: &PathBuf) -> Result<Vessel, StorageError> {
+        return Vessel::new(
+            path.clone(),
+            Bucketing::Fixed(1000),
+            LatePolicy::Reject,
+            Retention::Forever,
+            Durability::None,
+            TornTailPolicy::Truncate,
+            ValueType::F64,
+            Layout::Flat,
+            Arc::new(DescriptorCache::new(8)));
+    }
+
+    #[test]
+    fn second_writer_is_turned_away() {
+        let dir = TempDir::new("lock");
+        let path = dir.join("stream");
+
+        let vessel = open(&path).unwrap();
+
+        match open(&path) {
+            Err(StorageError::Locked { path: locked, pid }) => {
+                assert_eq!(locked, path);
+                assert_eq!(pid, Some(std::process::id()));
+            },
+            Err(e) => panic!("expected the stream to be locked, got {}", e),
+            Ok(_) => panic!("expected the stream to be locked")
+        }
+
+        // The lock goes with the vessel.
+        drop(vessel);
+        open(&path).unwrap();
+    }
+}
@@ -1,4 +1,19 @@
 pub mod vessel2;
 pub mod file_system;
 pub mod domain;
-pub mod file_handle;
\ No newline at end of file
+pub mod file_handle;
+pub mod write_ahead_log;
+pub mod late_data;
+pub mod tombstones;
+pub mod retention;
+pub mod error;
+pub mod durability;
+pub mod recovery;
+pub mod repage;
+pub mod layout;
+pub mod page_index;
+pub mod lock;
+pub mod reader;
+pub mod descriptors;
+#[cfg(test)]
+pub mod testing;
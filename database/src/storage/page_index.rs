@@ -0,0 +1,279 @@
+use std::collections::BTreeMap;
+use std::ops::Bound::{Excluded, Included, Unbounded};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use log::warn;
+use crate::Blob;
+use crate::domain::UnixTime;
+use crate::storage::descriptors::DescriptorCache;
+use crate::storage::domain::bucket::{Bucket, Bucketing};
+use crate::storage::domain::data_page::DataPage;
+use crate::storage::domain::mapped_page::PageView;
+use crate::storage::domain::value::ValueType;
+use crate::storage::durability::Durability;
+use crate::storage::error::StorageError;
+use crate::storage::file_handle::FileHandle;
+use crate::storage::file_system::FileSystem;
+use crate::storage::layout::{Layout, SHARDING};
+use crate::storage::recovery::{repair_tail, TornTail, TornTailPolicy};
+
+// The pages of a stream, by bucket. Sharded pages are only listed when
+// something first needs a page from their shard, so opening a stream
+// with years of pages only reads the directories of the shards it uses.
+//
+// The index is shared between the vessel and its readers on other
+// threads. Its locks are only held to look up or list pages, never while
+// a page is read, so readers don't hold up the vessel's writes.
+pub struct PageIndex {
+    bucketing: Bucketing,
+    durability: Durability,
+    // None when the stream is opened read-only, and torn pages are left
+    // for the writer to repair.
+    torn_tail: Option<TornTailPolicy>,
+    value_type: ValueType,
+    descriptors: Arc<DescriptorCache>,
+    files: RwLock<BTreeMap<Bucket, Arc<RwLock<FileHandle>>>>,
+    // The shards, by the start of their month, not listed yet. This is
+    // locked while a shard is listed, and before `files` whenever both
+    // are, so nothing sees a shard as neither listed nor unlisted.
+    unloaded: Mutex<BTreeMap<UnixTime, PathBuf>>,
+    torn: Mutex<Vec<TornTail>>
+}
+
+impl PageIndex {
+    pub fn open(
+        path: &PathBuf,
+        layout: Layout,
+        bucketing: Bucketing,
+        durability: Durability,
+        torn_tail: Option<TornTailPolicy>,
+        value_type: ValueType,
+        descriptors: Arc<DescriptorCache>) -> Result<PageIndex, StorageError> {
+
+        let index = PageIndex {
+            bucketing,
+            durability,
+            torn_tail,
+            value_type,
+            descriptors,
+            files: RwLock::new(BTreeMap::new()),
+            unloaded: Mutex::new(BTreeMap::new()),
+            torn: Mutex::new(Vec::new())
+        };
+
+        match layout {
+            Layout::Flat => index.add_pages(FileSystem::list_dir(path)?)?,
+            Layout::Sharded => index.unloaded.lock().unwrap().extend(FileSystem::list_shards(path)?)
+        }
+
+        return Ok(index);
+    }
+
+    fn add_pages(&self, pages: Vec<(UnixTime, PathBuf)>) -> Result<(), StorageError> {
+        for (date, page_path) in pages {
+            // Pages are named after the start of their bucket, so any other
+            // name means the stream was written with different bucketing.
+            if !self.bucketing.is_start(date) {
+                return Err(StorageError::Misaligned { path: page_path, bucketing: self.bucketing });
+            }
+
+            // A crash part way through a flush can leave a partial record
+            // at the end of a page, which the next flush would append after.
+            if let Some(header) = FileSystem::check_header(&page_path)? {
+                header.check_type(&page_path, self.value_type)?;
+
+                if let Some(torn_tail) = self.torn_tail {
+                    if let Some(tail) = repair_tail(&page_path, &header, torn_tail, self.durability)? {
+                        warn!("Recovered page: {}", tail);
+                        self.torn.lock().unwrap().push(tail);
+                    }
+                }
+            }
+
+            let bucket = Bucket::new(date, self.bucketing);
+            let node = FileHandle::new(page_path, bucket, self.value_type, self.descriptors.clone());
+
+            self.files.write().unwrap().insert(bucket, Arc::new(RwLock::new(node)));
+        }
+
+        return Ok(());
+    }
+
+    fn load(&self, shard: UnixTime) -> Result<(), StorageError> {
+        let mut unloaded = self.unloaded.lock().unwrap();
+
+        return match unloaded.remove(&shard) {
+            Some(dir) => self.add_pages(FileSystem::list_dir(&dir)?),
+            None => Ok(())
+        };
+    }
+
+    fn load_all(&self) -> Result<(), StorageError> {
+        let shards = self.unloaded.lock().unwrap().keys().cloned().collect::<Vec<UnixTime>>();
+
+        for shard in shards {
+            self.load(shard)?;
+        }
+
+        return Ok(());
+    }
+
+    fn shard_of(bucket: Bucket) -> UnixTime {
+        return SHARDING.start(bucket.val);
+    }
+
+    // Partial records cut from the end of pages as they were listed.
+    pub fn torn(&self) -> Vec<TornTail> {
+        return self.torn.lock().unwrap().clone();
+    }
+
+    pub fn get(&self, bucket: Bucket) -> Result<Option<Arc<RwLock<FileHandle>>>, StorageError> {
+        self.load(Self::shard_of(bucket))?;
+        return Ok(self.files.read().unwrap().get(&bucket).cloned());
+    }
+
+    pub fn len(&self) -> Result<usize, StorageError> {
+        self.load_all()?;
+        return Ok(self.files.read().unwrap().len());
+    }
+
+    // Each of these looks at the pages already listed, and then lists the
+    // nearest shard which could hold a closer page until there is none.
+
+    pub fn first(&self) -> Result<Option<Bucket>, StorageError> {
+        loop {
+            let unloaded = self.unloaded.lock().unwrap();
+            let shard = unloaded.keys().next().cloned();
+            let found = self.files.read().unwrap().keys().next().cloned();
+            drop(unloaded);
+
+            match shard {
+                Some(shard) if found.map_or(true, |v| shard <= Self::shard_of(v)) => self.load(shard)?,
+                _ => return Ok(found)
+            }
+        }
+    }
+
+    pub fn last(&self) -> Result<Option<Bucket>, StorageError> {
+        loop {
+            let unloaded = self.unloaded.lock().unwrap();
+            let shard = unloaded.keys().next_back().cloned();
+            let found = self.files.read().unwrap().keys().next_back().cloned();
+            drop(unloaded);
+
+            match shard {
+                Some(shard) if found.map_or(true, |v| shard >= Self::shard_of(v)) => self.load(shard)?,
+                _ => return Ok(found)
+            }
+        }
+    }
+
+    pub fn next(&self, bucket: Bucket) -> Result<Option<Bucket>, StorageError> {
+        loop {
+            let unloaded = self.unloaded.lock().unwrap();
+
+            let shard = unloaded
+                .range(Self::shard_of(bucket)..)
+                .next()
+                .map(|(k, _)| *k);
+
+            let found = self.files
+                .read()
+                .unwrap()
+                .range((Excluded(bucket), Unbounded))
+                .next()
+                .map(|(k, _)| *k);
+
+            drop(unloaded);
+
+            match shard {
+                Some(shard) if found.map_or(true, |v| shard <= Self::shard_of(v)) => self.load(shard)?,
+                _ => return Ok(found)
+            }
+        }
+    }
+
+    pub fn prev(&self, bucket: Bucket) -> Result<Option<Bucket>, StorageError> {
+        loop {
+            let unloaded = self.unloaded.lock().unwrap();
+
+            let shard = unloaded
+                .range((Unbounded, Included(Self::shard_of(bucket))))
+                .next_back()
+                .map(|(k, _)| *k);
+
+            let found = self.files
+                .read()
+                .unwrap()
+                .range(..bucket)
+                .next_back()
+                .map(|(k, _)| *k);
+
+            drop(unloaded);
+
+            match shard {
+                Some(shard) if found.map_or(true, |v| shard >= Self::shard_of(v)) => self.load(shard)?,
+                _ => return Ok(found)
+            }
+        }
+    }
+
+    pub fn insert(&self, bucket: Bucket, file: Arc<RwLock<FileHandle>>) {
+        self.files.write().unwrap().insert(bucket, file);
+    }
+
+    pub fn remove(&self, bucket: Bucket) -> Result<Option<Arc<RwLock<FileHandle>>>, StorageError> {
+        self.load(Self::shard_of(bucket))?;
+        return Ok(self.files.write().unwrap().remove(&bucket));
+    }
+
+    // Each read holds the page's own lock, which a flush to the page
+    // waits on, so only ever sees whole records.
+    pub fn read(&self, bucket: Bucket) -> Result<Vec<Blob>, StorageError> {
+        return match self.get(bucket)? {
+            Some(v) => DataPage::open_page(bucket, v).read(),
+            None => Ok(vec![])
+        };
+    }
+
+    pub fn read_range(&self, bucket: Bucket, from: UnixTime, to: UnixTime) -> Result<Vec<Blob>, StorageError> {
+        return match self.get(bucket)? {
+            Some(v) => DataPage::open_page(bucket, v).read_range(from, to),
+            None => Ok(vec![])
+        };
+    }
+
+    // A sealed page's records, read through its mapping.
+    pub fn view(&self, bucket: Bucket) -> Result<PageView, StorageError> {
+        let file = match self.get(bucket)? {
+            Some(v) => v,
+            None => return Ok(PageView::empty())
+        };
+
+        let mapped = file.read().unwrap().mapped()?;
+        return Ok(mapped.map_or(PageView::empty(), |v| v.view()));
+    }
+
+    pub fn read_tail(&self, bucket: Bucket, before: UnixTime, count: u64) -> Result<Vec<Blob>, StorageError> {
+        return match self.get(bucket)? {
+            Some(v) => DataPage::open_page(bucket, v).read_tail(before, count),
+            None => Ok(vec![])
+        };
+    }
+
+    // Pages are created before anything is flushed to them, so the last
+    // few can be empty after a crash.
+    pub fn last_time(&self) -> Result<Option<UnixTime>, StorageError> {
+        let mut bucket = self.last()?;
+
+        while let Some(v) = bucket {
+            if let Some(last) = self.read_tail(v, UnixTime::MAX, 1)?.last() {
+                return Ok(Some(last.timestamp));
+            }
+
+            bucket = self.prev(v)?;
+        }
+
+        return Ok(None);
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use crate::domain::UnixTime;
+use crate::storage::descriptors::DescriptorCache;
+use crate::storage::domain::blob::Blob;
+use crate::storage::domain::bucket::{Bucket, Bucketing};
+use crate::storage::domain::mapped_page::PageView;
+use crate::storage::domain::value::ValueType;
+use crate::storage::durability::Durability;
+use crate::storage::error::StorageError;
+use crate::storage::file_system::FileSystem;
+use crate::storage::layout::Layout;
+use crate::storage::lock::{LockMode, VesselLock};
+use crate::storage::page_index::PageIndex;
+use crate::storage::recovery::TornTailPolicy;
+use crate::storage::tombstones::Tombstones;
+use crate::storage::vessel2::{PageViews, ReverseVesselIterator, VesselIterator};
+
+const TAIL_CHUNK_SIZE: u64 = 1000;
+
+// Reads the pages of a vessel, from any thread. Readers share the page
+// index, write buffer and reorder buffer with the vessel, so see every
+// record the vessel has accepted, flushed or not, while it carries on
+// writing. A reader opened
+// on its own holds a shared lock on the stream for as long as any of its
+// clones are alive, so any number of processes can read a stream that
+// nothing is writing to.
+#[derive(Clone)]
+pub struct VesselReader {
+    pub path: PathBuf,
+    files: Arc<PageIndex>,
+    tombstones: Arc<RwLock<Tombstones>>,
+    pending: Arc<RwLock<Vec<Blob>>>,
+    held: Arc<RwLock<BTreeMap<UnixTime, Blob>>>,
+    bucketing: Bucketing,
+    lock: Option<Arc<VesselLock>>
+}
+
+impl VesselReader {
+    pub fn new(
+        path: PathBuf,
+        files: Arc<PageIndex>,
+        tombstones: Arc<RwLock<Tombstones>>,
+        pending: Arc<RwLock<Vec<Blob>>>,
+        held: Arc<RwLock<BTreeMap<UnixTime, Blob>>>,
+        bucketing: Bucketing) -> VesselReader {
+
+        return VesselReader {
+            path,
+            files,
+            tombstones,
+            pending,
+            held,
+            bucketing,
+            lock: None
+        };
+    }
+
+    pub fn open(
+        path: PathBuf,
+        bucketing: Bucketing,
+        value_type: ValueType,
+        layout: Layout,
+        descriptors: Arc<DescriptorCache>)
+        -> Result<VesselReader, StorageError>
+    {
+        let lock = VesselLock::acquire(&path, LockMode::Shared)?;
+
+        // Durability and torn tail policy only matter to whatever writes.
+        let (file_system, _) = FileSystem::new(
+            path,
+            bucketing,
+            Durability::None,
+            TornTailPolicy::Truncate,
+            value_type,
+            layout,
+            lock,
+            descriptors)?;
+
+        let mut reader = file_system.reader();
+        reader.lock = Some(file_system.lock().clone());
+
+        return Ok(reader);
+    }
+
+    pub fn page_count(&self) -> Result<usize, StorageError> {
+        return self.files.len();
+    }
+
+    // Includes records which haven't been flushed yet. None if the vessel
+    // holds nothing at all.
+    pub fn last_time(&self) -> Result<Option<UnixTime>, StorageError> {
+        let pending = self.pending();
+        let flushed = self.files.last_time()?;
+
+        return Ok(pending.last().map(|v| v.timestamp).max(flushed));
+    }
+
+    // As last_time, but zero for an empty vessel.
+    pub fn get_last_time(&self) -> Result<UnixTime, StorageError> {
+        return Ok(self.last_time()?.unwrap_or(0));
+    }
+
+    // The records accepted by the vessel but not yet flushed, in timestamp
+    // order. Those in the write buffer belong to the last page, and those
+    // held for reordering to it or to pages yet to be created. This has to
+    // be taken before the page is read, as records may be flushed at any
+    // time in between.
+    pub fn pending(&self) -> Vec<Blob> {
+        // Records move from the reorder buffer to the write buffer, so are
+        // taken in that order, and any seen in both are only kept once.
+        let held = self.held.read().unwrap().values().cloned().collect::<Vec<Blob>>();
+        let mut pending = self.pending.read().unwrap().clone();
+
+        let last = pending.last().map(|v| v.timestamp);
+        pending.extend(held.into_iter().filter(|v| last.map_or(true, |last| v.timestamp > last)));
+
+        return pending;
+    }
+
+    pub fn first_bucket(&self) -> Result<Option<Bucket>, StorageError> {
+        return self.files.first();
+    }
+
+    pub fn last_bucket(&self) -> Result<Option<Bucket>, StorageError> {
+        return self.files.last();
+    }
+
+    pub fn prev_bucket(&self, bucket: Bucket) -> Result<Option<Bucket>, StorageError> {
+        return self.files.prev(bucket);
+    }
+
+    pub fn next_bucket(&self, bucket: Bucket) -> Result<Option<Bucket>, StorageError> {
+        return self.files.next(bucket);
+    }
+
+    // As next_bucket, but also stopping at buckets with `pending` records,
+    // as those held for reordering may belong to pages not yet created.
+    pub fn next_pending_bucket(&self, bucket: Bucket, pending: &[Blob]) -> Result<Option<Bucket>, StorageError> {
+        let page = self.files.next(bucket)?;
+
+        let held = pending
+            .iter()
+            .map(|v| Bucket::for_time(v.timestamp, self.bucketing))
+            .find(|v| *v > bucket);
+
+        return Ok(match (page, held) {
+            (Some(page), Some(held)) => Some(page.min(held)),
+            (page, held) => page.or(held)
+        });
+    }
+
+    // Reads a whole page, or the part of it in [from, to), along with
+    // whatever of `pending` belongs there and hadn't yet been flushed.
+    pub fn read_page(
+        &self,
+        bucket: Bucket,
+        from: Option<UnixTime>,
+        to: Option<UnixTime>,
+        pending: &[Blob]) -> Result<Vec<Blob>, StorageError> {
+
+        return Ok(self.view_page(bucket, from, to, pending)?.into_vec());
+    }
+
+    // As read_page, but pages before the last are read through a memory
+    // mapping and handed back without copying their records, unless some
+    // of them have been deleted. The last page may still be written to, so
+    // is always read through a file.
+    pub fn view_page(
+        &self,
+        bucket: Bucket,
+        from: Option<UnixTime>,
+        to: Option<UnixTime>,
+        pending: &[Blob]) -> Result<PageView, StorageError> {
+
+        let from = from.unwrap_or(UnixTime::MIN);
+        let to = to.unwrap_or(UnixTime::MAX);
+
+        let sealed = self.files.last()?.map_or(false, |last| bucket < last);
+
+        let view = if sealed {
+            let view = self.files.view(bucket)?.range(from, to);
+            view.check()?;
+            view
+        } else if from == UnixTime::MIN && to == UnixTime::MAX {
+            PageView::Buffered(self.files.read(bucket)?)
+        } else {
+            PageView::Buffered(self.files.read_range(bucket, from, to)?)
+        };
+
+        // The buffer only ever holds the records after the last one on
+        // disk, so any which are no later than that have been flushed.
+        let flushed = view.last().map(|v| v.timestamp);
+
+        let unflushed: Vec<Blob> = pending
+            .iter()
+            .filter(|v| Bucket::for_time(v.timestamp, self.bucketing) == bucket)
+            .filter(|v| v.timestamp >= from && v.timestamp < to)
+            .filter(|v| flushed.map_or(true, |last| v.timestamp > last))
+            .copied()
+            .collect();
+
+        let tombstones = self.tombstones.read().unwrap();
+
+        let first = view.first().or(unflushed.first().copied());
+        let last = unflushed.last().copied().or(view.last());
+
+        let deleted = match (first, last) {
+            (Some(first), Some(last)) => tombstones.overlaps(first.timestamp, last.timestamp),
+            _ => false
+        };
+
+        if unflushed.is_empty() && !deleted {
+            return Ok(view);
+        }
+
+        let mut data = view.into_vec();
+        data.extend(unflushed);
+
+        tombstones.retain_live(&mut data);
+        return Ok(PageView::Buffered(data));
+    }
+
+    // Reads the last `count` records of a page with timestamps before
+    // `before`, leaving deleted records in so that they can be counted.
+    pub fn read_page_tail(&self, bucket: Bucket, before: UnixTime, count: u64) -> Result<Vec<Blob>, StorageError> {
+        return self.files.read_tail(bucket, before, count);
+    }
+
+    pub fn retain_live(&self, records: &mut Vec<Blob>) {
+        self.tombstones.read().unwrap().retain_live(records);
+    }
+
+    pub fn read_from(&self, from: UnixTime) -> VesselIterator {
+        return VesselIterator::new(
+            self.clone(),
+            Bucket::for_time(from, self.bucketing),
+            Some(from.saturating_add(1)),
+            None);
+    }
+
+    // Reads every record in the vessel, a page at a time.
+    pub fn read_all(&self) -> Result<VesselIterator, StorageError> {
+        let first = self.files.first()?;
+
+        return Ok(VesselIterator::new(
+            self.clone(),
+            first.unwrap_or(Bucket::epoch(self.bucketing)),
+            None,
+            None));
+    }
+
+    // Reads the records with timestamps in [from, to), stopping at the
+    // page which holds `to` rather than reading to the end of the vessel.
+    pub fn read_range(&self, from: UnixTime, to: UnixTime) -> VesselIterator {
+        return VesselIterator::new(
+            self.clone(),
+            Bucket::for_time(from, self.bucketing),
+            Some(from),
+            Some(to));
+    }
+
+    // As read_from and read_range, but hand out views onto the pages
+    // rather than copying each one into a batch.
+    pub fn view_from(&self, from: UnixTime) -> PageViews {
+        return PageViews::new(self.read_from(from));
+    }
+
+    pub fn view_range(&self, from: UnixTime, to: UnixTime) -> PageViews {
+        return PageViews::new(self.read_range(from, to));
+    }
+
+    // Walks the vessel backwards from the most recent record.
+    pub fn read_reverse(&self) -> Result<ReverseVesselIterator, StorageError> {
+        return Ok(ReverseVesselIterator::new(self.clone(), TAIL_CHUNK_SIZE));
+    }
+
+    // The last n records in the vessel, oldest first.
+    pub fn tail(&self, n: usize) -> Result<Vec<Blob>, StorageError> {
+        let mut it = ReverseVesselIterator::new(self.clone(), n as u64);
+
+        let mut records = Vec::with_capacity(n);
+
+        while records.len() < n {
+            match it.next() {
+                Some(batch) => records.extend(batch?),
+                None => break
+            }
+
+            it.chunk_size = (n - records.len()) as u64;
+        }
+
+        records.reverse();
+        return Ok(records);
+    }
+}
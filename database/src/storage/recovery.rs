@@ -0,0 +1,108 @@
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::OpenOptions;
+use std::path::PathBuf;
+use crate::storage::domain::page_header::{HEADER_SIZE, PageEncoding, PageHeader};
+use crate::storage::durability::Durability;
+use crate::storage::error::StorageError;
+
+// Bytes cut from the end of a page are kept in a file alongside it, named
+// after the page and the offset they were cut from.
+pub const QUARANTINE_SUFFIX: &str = ".torn";
+
+// What to do with a partial record left at the end of a page by a crash
+// part way through a flush.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TornTailPolicy {
+    // Cut the partial record off and throw it away.
+    Truncate,
+    // Cut the partial record off, keeping its bytes in a quarantine file.
+    Quarantine
+}
+
+#[derive(Clone, Debug)]
+pub struct TornTail {
+    pub path: PathBuf,
+    // Where the partial record started.
+    pub offset: u64,
+    pub len: u64,
+    pub quarantine: Option<PathBuf>
+}
+
+impl Display for TornTail {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cut a partial record of {} bytes from {} at offset {}",
+            self.len, self.path.display(), self.offset)?;
+
+        if let Some(quarantine) = &self.quarantine {
+            write!(f, ", keeping it in {}", quarantine.display())?;
+        }
+
+        return Ok(());
+    }
+}
+
+// Everything a vessel had to repair when it was opened.
+#[derive(Clone, Debug, Default)]
+pub struct RecoveryReport {
+    pub torn: Vec<TornTail>,
+    // Records replayed from the write-ahead log.
+    pub replayed: usize
+}
+
+// Cuts a partial record off the end of a raw page, so that the next flush
+// appends on a record boundary. Compressed pages are written whole through
+// a rename, so can't be torn.
+pub fn repair_tail(
+    path: &PathBuf,
+    header: &PageHeader,
+    policy: TornTailPolicy,
+    durability: Durability) -> Result<Option<TornTail>, StorageError> {
+
+    if header.encoding != PageEncoding::Raw {
+        return Ok(None);
+    }
+
+    let len = fs::metadata(path).map_err(|e| StorageError::io(path, e))?.len();
+    let width = header.record_width() as u64;
+    let torn = (len - HEADER_SIZE as u64) % width;
+
+    if torn == 0 {
+        return Ok(None);
+    }
+
+    let offset = len - torn;
+
+    let quarantine = match policy {
+        TornTailPolicy::Truncate => None,
+        TornTailPolicy::Quarantine => {
+            let bytes = fs::read(path).map_err(|e| StorageError::io(path, e))?;
+
+            let mut name = path.file_name().unwrap().to_os_string();
+            name.push(format!(".{}{}", offset, QUARANTINE_SUFFIX));
+
+            let quarantine = path.with_file_name(name);
+            fs::write(&quarantine, &bytes[offset as usize..]).map_err(|e| StorageError::io(&quarantine, e))?;
+
+            Some(quarantine)
+        }
+    };
+
+    let file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| StorageError::io(path, e))?;
+
+    file.set_len(offset).map_err(|e| StorageError::io(path, e))?;
+
+    if durability != Durability::None {
+        file.sync_data().map_err(|e| StorageError::io(path, e))?;
+    }
+
+    return Ok(Some(TornTail {
+        path: path.clone(),
+        offset,
+        len: torn,
+        quarantine
+    }));
+}
@@ -0,0 +1,274 @@
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use log::{info, warn};
+use crate::data_structures::metadata::StreamMetadata;
+use crate::storage::descriptors::{DEFAULT_READ_DESCRIPTORS, DescriptorCache};
+use crate::storage::domain::bucket::Bucketing;
+use crate::storage::domain::value::ValueType;
+use crate::storage::durability::{Durability, sync_dir};
+use crate::storage::error::StorageError;
+use crate::storage::late_data::LatePolicy;
+use crate::storage::layout::Layout;
+use crate::storage::lock::{LockMode, VesselLock, lock_path};
+use crate::storage::recovery::{QUARANTINE_SUFFIX, TornTailPolicy};
+use crate::storage::retention::Retention;
+use crate::storage::vessel2::Vessel;
+
+// A stream is rewritten into a directory alongside it, which is swapped
+// in once complete. The old directory is moved aside during the swap.
+pub const REPAGE_SUFFIX: &str = ".repage";
+pub const REPLACED_SUFFIX: &str = ".replaced";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RepageReport {
+    pub records: usize,
+    pub pages_before: usize,
+    pub pages_after: usize
+}
+
+// Rewrites a stream written with `from` bucketing into pages bucketed by
+// `to`. Page files are named after the start of their bucket, so a stream
+// can only be read with the bucketing it was written with.
+// The stream's lock is held from before anything is touched until the
+// swap is done, so the stream mustn't be open, or being repaged, elsewhere.
+pub fn repage(
+    path: &PathBuf,
+    from: Bucketing,
+    to: Bucketing,
+    durability: Durability,
+    value_type: ValueType,
+    layout: Layout) -> Result<RepageReport, StorageError> {
+
+    let lock = VesselLock::acquire(path, LockMode::Exclusive)?;
+
+    finish_swap(path)?;
+
+    let temp = sibling(path, REPAGE_SUFFIX);
+
+    // Whatever is here was left by a repage which never got as far as
+    // the swap, so the stream itself is still intact.
+    if temp.exists() {
+        fs::remove_dir_all(&temp).map_err(|e| StorageError::io(&temp, e))?;
+    }
+
+    // Opening the stream replays its write-ahead log and cuts off any torn
+    // records, so that everything is in its pages before they are read.
+    // Nothing may be dropped, whatever the stream's retention policy.
+    let descriptors = Arc::new(DescriptorCache::new(DEFAULT_READ_DESCRIPTORS));
+
+    let source = Vessel::with_lock(
+        path.clone(),
+        from,
+        LatePolicy::Reject,
+        Retention::Forever,
+        durability,
+        TornTailPolicy::Quarantine,
+        value_type,
+        layout,
+        descriptors.clone(),
+        lock)?;
+
+    let mut target = Vessel::new(
+        temp.clone(),
+        to,
+        LatePolicy::Reject,
+        Retention::Forever,
+        durability,
+        TornTailPolicy::Truncate,
+        value_type,
+        layout,
+        descriptors)?;
+
+    let mut records = 0;
+
+    // Deleted records are left behind, as they are hidden when read.
+    for batch in source.read_all()? {
+        let batch = batch?;
+        records += batch.len();
+
+        target.write(Rc::new(batch))?;
+    }
+
+    target.flush()?;
+    target.compact()?;
+
+    let pages_before = source.page_count()?;
+    let pages_after = target.page_count()?;
+
+    copy_extras(path, &temp, to)?;
+
+    if durability != Durability::None {
+        sync_dir(&temp)?;
+    }
+
+    // The source holds the stream's lock, which sits beside the directory
+    // and so stays put while the directories are renamed under it.
+    swap(path, &temp, durability)?;
+
+    drop(source);
+    drop(target);
+
+    let temp_lock = lock_path(&temp);
+    fs::remove_file(&temp_lock).map_err(|e| StorageError::io(&temp_lock, e))?;
+
+    info!("Repaged {} records of {} from {} pages bucketed {} to {} pages bucketed {}",
+        records, path.display(), pages_before, from, pages_after, to);
+
+    return Ok(RepageReport {
+        records,
+        pages_before,
+        pages_after
+    });
+}
+
+// Carries over what isn't rebuilt from the records, with the metadata
+// brought up to date with the new bucketing.
+fn copy_extras(path: &PathBuf, temp: &PathBuf, to: Bucketing) -> Result<(), StorageError> {
+    if let Some(mut metadata) = StreamMetadata::read(path)? {
+        metadata.set("bucketing", to.to_string());
+        metadata.write(temp)?;
+    }
+
+    for entry in fs::read_dir(path).map_err(|e| StorageError::io(path, e))? {
+        let entry = entry.map_err(|e| StorageError::io(path, e))?;
+
+        if entry.file_name().to_string_lossy().ends_with(QUARANTINE_SUFFIX) {
+            let copy = temp.join(entry.file_name());
+            fs::copy(entry.path(), &copy).map_err(|e| StorageError::io(&copy, e))?;
+        }
+    }
+
+    return Ok(());
+}
+
+// Renames can't replace a directory that isn't empty, so the old stream
+// is moved aside first. finish_swap completes this after a crash.
+fn swap(path: &PathBuf, temp: &PathBuf, durability: Durability) -> Result<(), StorageError> {
+    let replaced = sibling(path, REPLACED_SUFFIX);
+
+    fs::rename(path, &replaced).map_err(|e| StorageError::io(path, e))?;
+    fs::rename(temp, path).map_err(|e| StorageError::io(temp, e))?;
+
+    if durability != Durability::None {
+        sync_dir(&parent(path))?;
+    }
+
+    fs::remove_dir_all(&replaced).map_err(|e| StorageError::io(&replaced, e))?;
+
+    return Ok(());
+}
+
+// Puts a stream back together after a crash part way through a swap. The
+// old stream is only moved aside once the new one is complete, so
+// whichever is found alongside the stream can be trusted.
+pub fn finish_swap(path: &PathBuf) -> Result<(), StorageError> {
+    let temp = sibling(path, REPAGE_SUFFIX);
+    let replaced = sibling(path, REPLACED_SUFFIX);
+
+    if !replaced.exists() {
+        return Ok(());
+    }
+
+    if !path.exists() {
+        let from = if temp.exists() { &temp } else { &replaced };
+
+        warn!("Completing an interrupted repage of {}", path.display());
+        fs::rename(from, path).map_err(|e| StorageError::io(from, e))?;
+        sync_dir(&parent(path))?;
+    }
+
+    if replaced.exists() {
+        fs::remove_dir_all(&replaced).map_err(|e| StorageError::io(&replaced, e))?;
+    }
+
+    return Ok(());
+}
+
+fn sibling(path: &PathBuf, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(suffix);
+
+    return path.with_file_name(name);
+}
+
+fn parent(path: &PathBuf) -> PathBuf {
+    return match path.parent() {
+        Some(v) if !v.as_os_str().is_empty() => v.to_path_buf(),
+        _ => PathBuf::from(".")
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use crate::domain::UnixTime;
+    use crate::storage::descriptors::DescriptorCache;
+    use crate::storage::domain::blob::Blob;
+    use crate::storage::domain::bucket::Bucketing;
+    use crate::storage::domain::value::{Value, ValueType};
+    use crate::storage::durability::Durability;
+    use crate::storage::late_data::LatePolicy;
+    use crate::storage::layout::Layout;
+    use crate::storage::recovery::TornTailPolicy;
+    use crate::storage::retention::Retention;
+    use crate::storage::testing::TempDir;
+    use crate::storage::vessel2::Vessel;
+    use super::{REPAGE_SUFFIX, repage, sibling};
+
+    fn open(path: &PathBuf, bucketing: Bucketing) -> Vessel {
+        return Vessel::new(
+            path.clone(),
+            bucketing,
+            LatePolicy::Reject,
+            Retention::Forever,
+            Durability::None,
+            TornTailPolicy::Truncate,
+            ValueType::F64,
+            Layout::Flat,
+            Arc::new(DescriptorCache::new(8))).unwrap();
+    }
+
+    fn records(vessel: &Vessel) -> Vec<(UnixTime, Value)> {
+        return vessel.read_all().unwrap()
+            .flat_map(|v| v.unwrap())
+            .map(|v| (v.timestamp, v.data))
+            .collect();
+    }
+
+    #[test]
+    fn repaged_stream_reopens_with_the_same_records() {
+        let dir = TempDir::new("repage");
+        let path = dir.join("stream");
+
+        // Some records are left in the write-ahead log, for the repage to
+        // pick up as it opens the stream.
+        let mut vessel = open(&path, Bucketing::Fixed(1000));
+        vessel.write(Rc::new((1..5000).map(|t| Blob::new(t, t as f64)).collect())).unwrap();
+        vessel.flush().unwrap();
+        vessel.write(Rc::new((5000..5500).map(|t| Blob::new(t, t as f64)).collect())).unwrap();
+
+        let before = records(&vessel);
+        drop(vessel);
+
+        let report = repage(
+            &path,
+            Bucketing::Fixed(1000),
+            Bucketing::Fixed(4000),
+            Durability::None,
+            ValueType::F64,
+            Layout::Flat).unwrap();
+
+        assert_eq!(report.records, 5499);
+        assert_eq!(report.pages_after, 2);
+        assert!(!sibling(&path, REPAGE_SUFFIX).exists());
+
+        let vessel = open(&path, Bucketing::Fixed(4000));
+
+        assert_eq!(records(&vessel), before);
+        assert_eq!(vessel.page_count().unwrap(), 2);
+    }
+}
@@ -0,0 +1,10 @@
+// How long a vessel keeps its pages for. Whole pages are dropped, so data
+// is kept for up to one page length longer than asked for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Retention {
+    Forever,
+    // Drop pages which only hold records older than this many milliseconds.
+    MaxAge(i64),
+    // Keep at most this many pages, dropping the oldest first.
+    MaxPages(usize)
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use crate::domain::UnixTime;
+use crate::storage::domain::blob::Blob;
+use crate::storage::domain::data_page::DataPage;
+use crate::storage::domain::page_header::PageError;
+
+pub const TOMBSTONE_FILE_NAME: &str = "tombstones";
+
+// | from (8) | to (8) | crc32 (4) | per range, little endian.
+const ENTRY_SIZE: usize = 20;
+
+// Ranges of a vessel which have been deleted, but are still in the page
+// files until the next purge. Readers filter against these so that a
+// delete is visible straight away.
+pub struct Tombstones {
+    path: PathBuf,
+    ranges: Vec<(UnixTime, UnixTime)>
+}
+
+impl Tombstones {
+    pub fn open(dir: &PathBuf) -> Result<Tombstones, PageError> {
+        let path = dir.join(TOMBSTONE_FILE_NAME);
+
+        let bytes = match fs::read(&path) {
+            Ok(v) => v,
+            Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+            Err(e) => return Err(PageError::io(&path, e))
+        };
+
+        let mut ranges = Vec::new();
+        let mut entries = bytes.chunks_exact(ENTRY_SIZE);
+        let mut offset = 0u64;
+
+        for entry in &mut entries {
+            let expected = u32::from_le_bytes(entry[16..20].try_into().unwrap());
+            let found = crc32fast::hash(&entry[0..16]);
+
+            if expected != found {
+                return Err(PageError::Corrupt { path, offset, expected, found });
+            }
+
+            let from = i64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let to = i64::from_le_bytes(entry[8..16].try_into().unwrap());
+
+            ranges.push((from, to));
+            offset += ENTRY_SIZE as u64;
+        }
+
+        if entries.remainder().len() > 0 {
+            return Err(PageError::TruncatedRecord {
+                path,
+                offset,
+                len: entries.remainder().len()
+            });
+        }
+
+        return Ok(Tombstones {
+            path,
+            ranges
+        });
+    }
+
+    pub fn ranges(&self) -> &[(UnixTime, UnixTime)] {
+        return &self.ranges;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.ranges.is_empty();
+    }
+
+    // Deletes [from, to), persisting the tombstone before returning.
+    pub fn add(&mut self, from: UnixTime, to: UnixTime) -> Result<(), PageError> {
+        self.ranges.push((from, to));
+        return self.save();
+    }
+
+    pub fn clear(&mut self) -> Result<(), PageError> {
+        self.ranges.clear();
+        return self.save();
+    }
+
+    pub fn contains(&self, timestamp: UnixTime) -> bool {
+        return self.ranges
+            .iter()
+            .any(|(from, to)| timestamp >= *from && timestamp < *to);
+    }
+
+    // Whether anything in [from, to] has been deleted.
+    pub fn overlaps(&self, from: UnixTime, to: UnixTime) -> bool {
+        return self.ranges
+            .iter()
+            .any(|(start, end)| from < *end && to >= *start);
+    }
+
+    pub fn retain_live(&self, records: &mut Vec<Blob>) {
+        if self.ranges.is_empty() {
+            return;
+        }
+
+        records.retain(|v| !self.contains(v.timestamp));
+    }
+
+    fn save(&self) -> Result<(), PageError> {
+        let mut bytes = Vec::with_capacity(self.ranges.len() * ENTRY_SIZE);
+
+        for (from, to) in &self.ranges {
+            let start = bytes.len();
+
+            bytes.extend_from_slice(&from.to_le_bytes());
+            bytes.extend_from_slice(&to.to_le_bytes());
+
+            let checksum = crc32fast::hash(&bytes[start..]);
+            bytes.extend_from_slice(&checksum.to_le_bytes());
+        }
+
+        return DataPage::replace(&self.path, &bytes);
+    }
+}
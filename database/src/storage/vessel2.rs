@@ -1,5 +1,5 @@
 use std::borrow::{Borrow, BorrowMut};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::{fs, thread};
 use std::cell::RefCell;
 use std::fs::{DirEntry, File, OpenOptions};
@@ -11,17 +11,43 @@ use std::sync::{Arc, Mutex, RwLock};
 use std::time::Duration;
 use crate::{ArcRead, threading, UnixTime};
 use crate::storage::domain::blob::Blob;
-use crate::storage::domain::bucket::Bucket;
+use crate::storage::domain::bucket::{Bucket, Bucketing};
 use crate::storage::domain::data_page::DataPage;
+use crate::storage::domain::mapped_page::PageView;
+use crate::storage::domain::value::ValueType;
+use crate::storage::descriptors::DescriptorCache;
+use crate::storage::durability::{Durability, SyncState};
+use crate::storage::error::StorageError;
 use crate::storage::file_system::FileSystem;
+use crate::storage::layout::Layout;
+use crate::storage::lock::{LockMode, VesselLock};
+use crate::storage::repage::finish_swap;
+use crate::storage::late_data::{LateDataCounters, LateDataError, LatePolicy, ReorderBuffer};
+use crate::storage::reader::VesselReader;
+use crate::storage::recovery::{RecoveryReport, TornTailPolicy};
+use crate::storage::retention::Retention;
+use crate::storage::write_ahead_log::WriteAheadLog;
 use crate::threading::ArcRw;
+use chrono::Utc;
+use log::info;
 
 pub struct Vessel {
     pub path: PathBuf,
     file_system: Rc<RefCell<FileSystem>>,
     current_page: Option<DataPage>,
-    page_length: i64,
-    last: UnixTime
+    wal: WriteAheadLog,
+    late_policy: LatePolicy,
+    reorder: Option<ReorderBuffer>,
+    late_data: LateDataCounters,
+    retention: Retention,
+    sync: SyncState,
+    recovery: RecoveryReport,
+    value_type: ValueType,
+    bucketing: Bucketing,
+    reader: VesselReader,
+    // None until the first record is stored. It compares below any Some,
+    // so every record is later than an empty vessel's last.
+    last: Option<UnixTime>
 }
 
 const BUFFER_SIZE: i32 = 1000;
@@ -29,52 +55,398 @@ const BUFFER_SIZE: i32 = 1000;
 impl Vessel {
     pub fn new(
         path_buf: PathBuf,
-        page_length: i64)
-        -> Vessel
+        bucketing: Bucketing,
+        late_policy: LatePolicy,
+        retention: Retention,
+        durability: Durability,
+        torn_tail: TornTailPolicy,
+        value_type: ValueType,
+        layout: Layout,
+        descriptors: Arc<DescriptorCache>)
+        -> Result<Vessel, StorageError>
+    {
+        let lock = VesselLock::acquire(&path_buf, LockMode::Exclusive)?;
+
+        // Only once the lock is held can a repage cut short by a crash be
+        // told apart from one still running.
+        finish_swap(&path_buf)?;
+
+        return Self::with_lock(
+            path_buf,
+            bucketing,
+            late_policy,
+            retention,
+            durability,
+            torn_tail,
+            value_type,
+            layout,
+            descriptors,
+            lock);
+    }
+
+    // As new, for a caller which already holds the stream's exclusive lock
+    // and has finished any swap left behind.
+    pub fn with_lock(
+        path_buf: PathBuf,
+        bucketing: Bucketing,
+        late_policy: LatePolicy,
+        retention: Retention,
+        durability: Durability,
+        torn_tail: TornTailPolicy,
+        value_type: ValueType,
+        layout: Layout,
+        descriptors: Arc<DescriptorCache>,
+        lock: VesselLock)
+        -> Result<Vessel, StorageError>
     {
         let path = path_buf;
 
         let (mut file_system, page) = FileSystem::new(
             path.clone(),
-        page_length);
+            bucketing,
+            durability,
+            torn_tail,
+            value_type,
+            layout,
+            lock,
+            descriptors)?;
 
         let data_page = page;
-        let last = *&file_system.get_last_time();
+        let last = file_system.get_last_time()?;
+
+        let recovery = RecoveryReport {
+            torn: file_system.torn(),
+            replayed: 0
+        };
 
+        let reorder = match late_policy {
+            LatePolicy::Reorder(window) => Some(ReorderBuffer::new(window, file_system.held())),
+            _ => None
+        };
+
+        let reader = file_system.reader();
         let fs =  Rc::new(RefCell::new(file_system));
+        let wal = WriteAheadLog::open(&path, value_type, durability)?;
 
-        let vessel =  Vessel {
+        let mut vessel =  Vessel {
             path,
             file_system: fs.clone(),
             current_page: data_page,
-            page_length: page_length as i64,
+            wal,
+            late_policy,
+            reorder,
+            late_data: LateDataCounters::new(),
+            retention,
+            sync: SyncState::new(durability),
+            recovery,
+            value_type,
+            bucketing,
+            reader,
             last
         };
 
-        return vessel;
+        vessel.recover()?;
+        return Ok(vessel);
+    }
+
+    // Anything left in the log wasn't flushed before the last shutdown.
+    // Records no later than the last persisted timestamp either made it
+    // to disk or were rejected at the time, so only the rest are replayed.
+    fn recover(&mut self) -> Result<(), StorageError> {
+        let last = self.last;
+        let pending = self.wal.read()?
+            .into_iter()
+            .filter(|v| Some(v.timestamp) > last)
+            .collect::<Vec<Blob>>();
+
+        if pending.len() > 0 {
+            info!("Replaying {} records from the write-ahead log of {}",
+                pending.len(), self.path.display());
+
+            self.accept(&pending)?;
+        }
+
+        self.recovery.replayed = pending.len();
+
+        return self.flush();
     }
 
-    pub fn flush(&mut self) {
+    // What had to be repaired when the vessel was opened.
+    pub fn recovery(&self) -> &RecoveryReport {
+        return &self.recovery;
+    }
+
+    pub fn flush(&mut self) -> Result<(), StorageError> {
         let page = &mut self.current_page;
 
         if page.is_some() {
-            page.as_mut().unwrap().flush();
+            let page = page.as_mut().unwrap();
+
+            page.flush(&mut self.sync)?;
+        }
+
+        return self.reset_wal();
+    }
+
+    // Returns the records which went into the pages, in timestamp order,
+    // which is all that anything downstream should see. Records held in
+    // the reorder buffer come out of a later write, and late records which
+    // were rewritten into older pages aren't returned.
+    pub fn write(&mut self, records: Rc<Vec<Blob>>) -> Result<Vec<Blob>, StorageError> {
+        self.check_types(&records)?;
+        self.log(&records)?;
+
+        let (accepted, rejected) = self.accept(&records)?;
+        self.sync.tick()?;
+
+        if rejected.is_empty() {
+            return Ok(accepted);
+        }
+
+        self.late_data.add_rejected(rejected.len());
+
+        return Err(StorageError::LateData(LateDataError {
+            path: self.path.clone(),
+            last: self.last,
+            rejected,
+            accepted
+        }));
+    }
+
+    pub fn value_type(&self) -> ValueType {
+        return self.value_type;
+    }
+
+    // Rejects a whole batch if any of it isn't of the declared type, as
+    // nothing else would stop it being written with the wrong meaning.
+    fn check_types(&self, records: &[Blob]) -> Result<(), StorageError> {
+        let wrong = records
+            .iter()
+            .find(|v| v.data.value_type() != self.value_type);
+
+        return match wrong {
+            Some(v) => Err(StorageError::TypeMismatch {
+                path: self.path.clone(),
+                expected: self.value_type,
+                found: v.data.value_type()
+            }),
+            None => Ok(())
+        };
+    }
+
+    pub fn late_data(&self) -> LateDataCounters {
+        return self.late_data.clone();
+    }
+
+    // Applies the late data policy to a batch, returning the records
+    // which went into the pages and those which were rejected.
+    fn accept(&mut self, records: &[Blob]) -> Result<(Vec<Blob>, Vec<Blob>), StorageError> {
+        let mut accepted = Vec::with_capacity(records.len());
+        let mut late = Vec::new();
+
+        match &mut self.reorder {
+            Some(buffer) => {
+                for record in records {
+                    let out_of_order = record.timestamp < buffer.max_seen();
+
+                    if Some(record.timestamp) <= self.last || !buffer.insert(*record) {
+                        late.push(*record);
+                    } else if out_of_order {
+                        self.late_data.add_reordered(1);
+                    }
+                }
+
+                accepted = buffer.ready();
+            }
+            None => {
+                let mut last = self.last;
+
+                for record in records {
+                    if Some(record.timestamp) > last {
+                        last = Some(record.timestamp);
+                        accepted.push(*record);
+                    } else {
+                        late.push(*record);
+                    }
+                }
+            }
+        }
+
+        // Turning the page flushes everything before it, so only what's
+        // buffered in the new page needs to stay in the log.
+        if self.apply_ready(&accepted)? {
+            self.reset_wal()?;
+        }
+
+        let rejected = match self.late_policy {
+            LatePolicy::Rewrite if late.len() > 0 => self.rewrite(late)?,
+            _ => late
+        };
+
+        return Ok((accepted, rejected));
+    }
+
+    // Inserts late records into the pages they belong to, returning any
+    // which duplicate a timestamp that is already stored.
+    fn rewrite(&mut self, late: Vec<Blob>) -> Result<Vec<Blob>, StorageError> {
+        let (rejected, inserted) = self.merge(late, false)?;
+        self.late_data.add_rewritten(inserted.len());
+
+        return Ok(rejected);
+    }
+
+    // Inserts records, replacing the value of any whose timestamp is
+    // already stored. Returns the records which changed what is stored,
+    // so that anything derived from them can be recomputed.
+    pub fn upsert(&mut self, records: Rc<Vec<Blob>>) -> Result<Vec<Blob>, StorageError> {
+        self.check_types(&records)?;
+
+        // The last correction for a timestamp in the batch wins.
+        let mut sorted = BTreeMap::new();
+        for record in records.iter() {
+            sorted.insert(record.timestamp, *record);
+        }
+
+        let (older, newer): (Vec<Blob>, Vec<Blob>) = sorted
+            .into_values()
+            .partition(|v| Some(v.timestamp) <= self.last);
+
+        let mut changed = Vec::new();
+
+        if newer.len() > 0 {
+            self.log(&newer)?;
+
+            match &mut self.reorder {
+                Some(buffer) => {
+                    for record in &newer {
+                        buffer.upsert(*record);
+                    }
+
+                    let ready = buffer.ready();
+                    if self.apply_ready(&ready)? {
+                        self.reset_wal()?;
+                    }
+                }
+                None => {
+                    if self.apply(&newer)? {
+                        self.reset_wal()?;
+                    }
+                }
+            }
+
+            changed.extend(newer);
+        }
+
+        if older.len() > 0 {
+            let (_, replaced) = self.merge(older, true)?;
+            changed.extend(replaced);
+        }
+
+        changed.sort_by_key(|v| v.timestamp);
+        return Ok(changed);
+    }
+
+    // Merges records into the pages they belong to, rewriting each page
+    // through a temporary file. Records whose timestamp is already stored
+    // either replace it or are returned as rejected. Also returns the
+    // records which changed a page.
+    fn merge(&mut self, records: Vec<Blob>, replace: bool) -> Result<(Vec<Blob>, Vec<Blob>), StorageError> {
+        // Get everything onto disk first, so that each page can be
+        // merged from its file alone. Deleted records are purged so that
+        // a tombstone can't hide what is about to be written.
+        self.flush()?;
+        self.purge()?;
+
+        let mut buckets: BTreeMap<Bucket, Vec<Blob>> = BTreeMap::new();
+
+        for record in records {
+            let bucket = Bucket::for_time(record.timestamp, self.bucketing);
+            buckets.entry(bucket).or_default().push(record);
+        }
+
+        let c = self.file_system.clone();
+        let mut fs = c.as_ref().borrow_mut();
+        let mut rejected = Vec::new();
+        let mut changed = Vec::new();
+
+        for (bucket, records) in buckets {
+            let page = fs.create_page(bucket)?;
+            let mut merged = page.read()?;
+            let count = changed.len();
+
+            for record in records {
+                match merged.binary_search_by_key(&record.timestamp, |v| v.timestamp) {
+                    Ok(idx) if replace => {
+                        if merged[idx].data.to_bits() != record.data.to_bits() {
+                            merged[idx] = record;
+                            changed.push(record);
+                        }
+                    },
+                    Ok(_) => rejected.push(record),
+                    Err(idx) => {
+                        merged.insert(idx, record);
+                        changed.push(record);
+                    }
+                }
+            }
+
+            if changed.len() > count {
+                page.rewrite(&merged)?;
+                fs.rewritten(bucket)?;
+            }
         }
+
+        return Ok((rejected, changed));
+    }
+
+    // Records are in the log before they're acknowledged, and synced
+    // along with the pages.
+    fn log(&mut self, records: &[Blob]) -> Result<(), StorageError> {
+        self.wal.append(records)?;
+        return self.sync.flushed(self.wal.path(), self.wal.file());
     }
 
-    pub fn write(&mut self, records: Rc<Vec<Blob>>) {
+    // Keeps only the records which haven't yet made it into a page file.
+    fn reset_wal(&mut self) -> Result<(), StorageError> {
+        let mut pending = self.current_page
+            .as_ref()
+            .map(|v| v.pending())
+            .unwrap_or_default();
+
+        if let Some(buffer) = &self.reorder {
+            pending.extend(buffer.pending());
+        }
+
+        return Ok(self.wal.reset(&pending)?);
+    }
+
+    // Applies records, releasing them from the reorder buffer once they're
+    // in the write buffer, so that readers find them in one or the other.
+    fn apply_ready(&mut self, records: &[Blob]) -> Result<bool, StorageError> {
+        let turned = self.apply(records);
+
+        if let Some(buffer) = &mut self.reorder {
+            buffer.release(records);
+        }
+
+        return turned;
+    }
+
+    // Buffers records into the current page, returning whether a page was turned.
+    fn apply(&mut self, records: &[Blob]) -> Result<bool, StorageError> {
         let mut this_page =  &mut self.current_page;
+        let mut turned = false;
 
-        for record in &*records {
+        for record in records {
             let record_bucket = Bucket::for_time(
                 record.timestamp,
-                self.page_length);
+                self.bucketing);
 
-            if self.last >= record.timestamp {
+            if self.last >= Some(record.timestamp) {
                 continue;
             }
 
-            self.last = record.timestamp;
+            self.last = Some(record.timestamp);
 
             let c = self.file_system.as_ref();
             let mut fs = c.borrow_mut();
@@ -84,82 +456,591 @@ impl Vessel {
                 Some(v) if v.bucket == record_bucket => {
                     v.write(record.clone());
                 },
-                Some(_) => {
+                Some(v) => {
+                    // Flush before giving up the page, so that a failure
+                    // leaves it in place with nothing lost.
+                    v.flush(&mut self.sync)?;
+
                     let old_page = this_page.take().unwrap();
-                    let mut next_page = fs.turn_page(old_page, record_bucket);
+                    let mut next_page = fs.turn_page(old_page, record_bucket, &mut self.sync)?;
 
                     next_page.write(record.clone());
                     this_page.replace(next_page);
+                    turned = true;
                 }
                 None => {
-                    let mut next_page = fs.create_page(record_bucket);
+                    let mut next_page = fs.create_page(record_bucket)?;
                     next_page.write(record.clone());
                     this_page.replace(next_page);
                 }
             }
         }
+
+        return Ok(turned);
     }
 
-    pub fn get_last_time(&self) -> UnixTime {
+    // Hides every record in [from, to) straight away. The records stay
+    // in the page files until the next purge.
+    pub fn delete_range(&mut self, from: UnixTime, to: UnixTime) -> Result<(), StorageError> {
+        // Records held for reordering have been accepted, so go too, rather
+        // than being stored after the delete. The flush below drops them
+        // from the log.
+        if let Some(buffer) = &mut self.reorder {
+            buffer.remove_range(from, to);
+        }
+
+        // Deleted records still waiting to be flushed would otherwise
+        // only be caught by the tombstone once they reach disk.
+        self.flush()?;
+
+        // Nothing after the last record has been written yet, and it
+        // shouldn't be hidden when it is.
+        let to = match self.last {
+            Some(v) => to.min(v.saturating_add(1)),
+            None => return Ok(())
+        };
+
+        if from >= to {
+            return Ok(());
+        }
+
+        let mut fs = self.file_system.as_ref().borrow_mut();
+        return fs.delete_range(from, to);
+    }
+
+    pub fn purge(&mut self) -> Result<(), StorageError> {
+        let mut fs = self.file_system.as_ref().borrow_mut();
+        let purged = fs.purge()?;
+
+        if purged > 0 {
+            info!("Purged deleted records from {} pages of {}", purged, self.path.display());
+        }
+
+        return Ok(());
+    }
+
+    // Drops the pages which have fallen outside of the retention policy.
+    pub fn enforce_retention(&mut self) -> Result<(), StorageError> {
+        let now = Utc::now().timestamp_millis();
+
+        let mut fs = self.file_system.as_ref().borrow_mut();
+        let removed = fs.expire(self.retention, now)?;
+
+        for path in removed {
+            info!("Retention {:?} dropped page {}", self.retention, path.display());
+        }
+
+        return Ok(());
+    }
+
+    // Syncs whatever group commit has held back once it has waited long
+    // enough, so that it doesn't wait on the next write.
+    pub fn tick(&mut self) -> Result<(), StorageError> {
+        return self.sync.tick();
+    }
+
+    pub fn compact(&mut self) -> Result<(), StorageError> {
+        self.purge()?;
+
         let fs: &RefCell<FileSystem> = self.file_system.borrow();
-        return fs.borrow().get_last_time().clone();
+        let compacted = fs.borrow_mut().compact()?;
+
+        if compacted > 0 {
+            info!("Compacted {} pages of {}", compacted, self.path.display());
+        }
+
+        return Ok(());
+    }
+
+    // A reader over the vessel's pages, which can be cloned and sent to
+    // other threads while the vessel carries on writing.
+    pub fn reader(&self) -> VesselReader {
+        return self.reader.clone();
+    }
+
+    pub fn page_count(&self) -> Result<usize, StorageError> {
+        return self.reader().page_count();
+    }
+
+    pub fn get_last_time(&self) -> Result<UnixTime, StorageError> {
+        return self.reader().get_last_time();
     }
 
     pub fn read_from(&self, from: UnixTime) -> VesselIterator {
-        return VesselIterator::new(
-            self.file_system.clone(),
-            Bucket::for_time(from, self.page_length),
-        Some(from.clone()));
+        return self.reader().read_from(from);
+    }
+
+    pub fn read_all(&self) -> Result<VesselIterator, StorageError> {
+        return self.reader().read_all();
+    }
+
+    pub fn read_range(&self, from: UnixTime, to: UnixTime) -> VesselIterator {
+        return self.reader().read_range(from, to);
+    }
+
+    pub fn view_from(&self, from: UnixTime) -> PageViews {
+        return self.reader().view_from(from);
+    }
+
+    pub fn view_range(&self, from: UnixTime, to: UnixTime) -> PageViews {
+        return self.reader().view_range(from, to);
+    }
+
+    pub fn read_reverse(&self) -> Result<ReverseVesselIterator, StorageError> {
+        return self.reader().read_reverse();
+    }
+
+    pub fn tail(&self, n: usize) -> Result<Vec<Blob>, StorageError> {
+        return self.reader().tail(n);
     }
 }
 
 pub struct VesselIterator {
-    pub fs: Rc<RefCell<FileSystem>>,
-    pub bucket: Bucket,
-    pub start: Option<UnixTime>
+    pub reader: VesselReader,
+    pub bucket: Option<Bucket>,
+    // Inclusive lower bound, only applied to the first page.
+    pub start: Option<UnixTime>,
+    // Exclusive upper bound.
+    pub end: Option<UnixTime>,
+    // What was still to be flushed when the first page was read.
+    pending: Vec<Blob>,
+    started: bool,
+    failed: bool
 }
 
 impl VesselIterator {
     pub fn new(
-        fs: Rc<RefCell<FileSystem>>,
+        reader: VesselReader,
         bucket: Bucket,
-        start: Option<UnixTime>) -> VesselIterator
+        start: Option<UnixTime>,
+        end: Option<UnixTime>) -> VesselIterator
     {
         return VesselIterator {
-            fs,
-            bucket,
-            start
+            reader,
+            bucket: Some(bucket),
+            start,
+            end,
+            pending: Vec::new(),
+            started: false,
+            failed: false
+        };
+    }
+
+    // The next page's records, without copying them out of its mapping.
+    pub fn next_view(&mut self) -> Option<Result<PageView, StorageError>> {
+        // A corrupt page ends the iteration, rather than skipping
+        // over it and handing out an incomplete series.
+        if self.failed {
+            return None;
+        }
+
+        // The first read stops the iteration at the last record accepted so
+        // far. Otherwise a reader on another thread could read the end of a
+        // page, then miss whatever was flushed to it before the next page.
+        if !self.started {
+            self.started = true;
+            self.pending = self.reader.pending();
+
+            // An empty vessel has nothing to read.
+            let limit = match self.reader.last_time() {
+                Ok(v) => v.map_or(UnixTime::MIN, |v| v.saturating_add(1)),
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
+
+            self.end = Some(self.end.map_or(limit, |v| v.min(limit)));
+        }
+
+        let bucket = self.bucket?;
+
+        if let Some(end) = self.end {
+            if bucket.val >= end {
+                return None;
+            }
+        }
+
+        // Only the first and last pages need searching for their bounds,
+        // every page in between is read in full.
+        let end = self.end.filter(|v| *v < bucket.next().val);
+
+        let data = match self.reader.view_page(bucket, self.start, end, &self.pending) {
+            Ok(v) => v,
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(e));
+            }
+        };
+
+        self.start = None;
+        self.bucket = match self.reader.next_pending_bucket(bucket, &self.pending) {
+            Ok(v) => v,
+            Err(e) => {
+                self.failed = true;
+                return Some(Err(e));
+            }
         };
+
+        return Some(Ok(data));
     }
 }
 
 impl Iterator for VesselIterator{
-    type Item = Vec::<Blob>;
+    type Item = Result<Vec::<Blob>, StorageError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let b = self.fs.as_ref().borrow();
+        return self.next_view().map(|v| v.map(PageView::into_vec));
+    }
+}
 
-        let mut data = b.read(self.bucket);
+// Reads a vessel a page at a time, as views onto the pages' mappings.
+pub struct PageViews {
+    inner: VesselIterator
+}
+
+impl PageViews {
+    pub fn new(inner: VesselIterator) -> PageViews {
+        return PageViews {
+            inner
+        };
+    }
+}
+
+impl Iterator for PageViews {
+    type Item = Result<PageView, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.inner.next_view();
+    }
+}
+
+// Hands out batches of records newest first, reading each page from the
+// end in chunks rather than loading it whole.
+pub struct ReverseVesselIterator {
+    pub reader: VesselReader,
+    pub bucket: Option<Bucket>,
+    pub chunk_size: u64,
+    // The oldest record handed out so far.
+    before: UnixTime,
+    // What was still to be flushed when the iteration started, which is
+    // newer than anything on disk so is handed out first.
+    pending: Vec<Blob>,
+    started: bool,
+    failed: bool
+}
+
+impl ReverseVesselIterator {
+    pub fn new(reader: VesselReader, chunk_size: u64) -> ReverseVesselIterator {
+        return ReverseVesselIterator {
+            reader,
+            bucket: None,
+            chunk_size,
+            before: UnixTime::MAX,
+            pending: Vec::new(),
+            started: false,
+            failed: false
+        };
+    }
+}
+
+impl Iterator for ReverseVesselIterator {
+    type Item = Result<Vec::<Blob>, StorageError>;
 
-        if data.len() == 0 {
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.chunk_size == 0 {
             return None;
         }
 
-        // As we read an entire page per call, for the first call
-        // we may need to truncate values prior to the start time.
-        if let Some(v) = self.start {
-            data = data
-                .into_iter()
-                .filter(|blob| blob.timestamp > v)
-                .into_iter()
-                .collect::<Vec::<Blob>>();
+        // The last page is found after taking the buffer, in case the
+        // vessel turned a page in between.
+        if !self.started {
+            self.started = true;
+            self.pending = self.reader.pending();
 
-            self.start = None;
+            self.bucket = match self.reader.last_bucket() {
+                Ok(v) => v,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
         }
 
-        let bucket = self.bucket.next();
-        self.bucket = bucket;
+        loop {
+            let end = self.pending.partition_point(|v| v.timestamp < self.before);
+
+            if end > 0 {
+                let start = end.saturating_sub(self.chunk_size as usize);
+                let mut data = self.pending[start..end].to_vec();
+
+                self.before = data[0].timestamp;
+                self.reader.retain_live(&mut data);
+
+                if data.len() > 0 {
+                    data.reverse();
+                    return Some(Ok(data));
+                }
+
+                continue;
+            }
+
+            let bucket = self.bucket?;
+
+            let mut data = match self.reader.read_page_tail(bucket, self.before, self.chunk_size) {
+                Ok(v) => v,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
+
+            // A short read means the start of the page has been reached.
+            if (data.len() as u64) < self.chunk_size {
+                self.bucket = match self.reader.prev_bucket(bucket) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        self.failed = true;
+                        return Some(Err(e));
+                    }
+                };
+            }
+
+            if let Some(first) = data.first() {
+                self.before = first.timestamp;
+            }
 
-        return Some(data);
+            self.reader.retain_live(&mut data);
+
+            if data.len() > 0 {
+                data.reverse();
+                return Some(Ok(data));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use crate::domain::UnixTime;
+    use crate::storage::descriptors::DescriptorCache;
+    use crate::storage::domain::blob::Blob;
+    use crate::storage::domain::bucket::Bucketing;
+    use crate::storage::domain::data_page::DataPage;
+    use crate::storage::domain::value::ValueType;
+    use crate::storage::durability::Durability;
+    use crate::storage::layout::Layout;
+    use crate::storage::late_data::LatePolicy;
+    use crate::storage::recovery::TornTailPolicy;
+    use crate::storage::retention::Retention;
+    use crate::storage::testing::TempDir;
+    use crate::storage::domain::page_header::{HEADER_SIZE, PageHeader};
+    use crate::storage::write_ahead_log::WAL_FILE_NAME;
+    use super::Vessel;
+
+    fn open(path: &PathBuf) -> Vessel {
+        return open_with(path, TornTailPolicy::Truncate);
+    }
+
+    fn open_with(path: &PathBuf, torn_tail: TornTailPolicy) -> Vessel {
+        return Vessel::new(
+            path.clone(),
+            Bucketing::Fixed(1000),
+            LatePolicy::Reject,
+            Retention::Forever,
+            Durability::None,
+            torn_tail,
+            ValueType::F64,
+            Layout::Flat,
+            Arc::new(DescriptorCache::new(8))).unwrap();
+    }
+
+    fn append_bytes(path: &PathBuf, bytes: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    fn write(vessel: &mut Vessel, from: UnixTime, to: UnixTime) {
+        vessel.write(Rc::new((from..to).map(|t| Blob::new(t, t as f64)).collect())).unwrap();
+    }
+
+    fn timestamps(batches: impl Iterator<Item=Vec<Blob>>) -> Vec<UnixTime> {
+        return batches.flatten().map(|v| v.timestamp).collect();
+    }
+
+    fn outside(from: UnixTime, to: UnixTime, deleted: &[(UnixTime, UnixTime)]) -> Vec<UnixTime> {
+        return (from..to)
+            .filter(|t| deleted.iter().all(|(start, end)| t < start || t >= end))
+            .collect();
+    }
+
+    fn stored(path: &PathBuf, bucket: UnixTime) -> Option<u64> {
+        return DataPage::summarise(&Layout::Flat.page_path(path, bucket))
+            .unwrap()
+            .map(|v| v.records);
+    }
+
+    #[test]
+    fn deleted_records_are_hidden() {
+        let dir = TempDir::new("vessel_delete");
+        let path = dir.join("stream");
+        let deleted = [(500, 1500), (2990, 3000)];
+
+        // The second range covers records still in the write buffer, and
+        // runs on past the last record, which shouldn't hide those written
+        // after the delete.
+        let mut vessel = open(&path);
+        write(&mut vessel, 1, 3000);
+        vessel.delete_range(500, 1500).unwrap();
+        vessel.delete_range(2990, 3100).unwrap();
+        write(&mut vessel, 3000, 3200);
+
+        let expected = outside(1, 3200, &deleted);
+
+        assert_eq!(timestamps(vessel.read_from(0).map(|v| v.unwrap())), expected);
+        assert_eq!(timestamps(vessel.read_range(0, 3200).map(|v| v.unwrap())), expected);
+        assert_eq!(timestamps(vessel.view_range(0, 3200).map(|v| v.unwrap().into_vec())), expected);
+        assert_eq!(timestamps(vessel.view_range(400, 1600).map(|v| v.unwrap().into_vec())), outside(400, 1600, &deleted));
+        assert_eq!(vessel.tail(5).unwrap().iter().map(|v| v.timestamp).collect::<Vec<UnixTime>>(), vec![3195, 3196, 3197, 3198, 3199]);
+
+        // Tombstones are kept with the pages, so still apply after a reopen.
+        drop(vessel);
+        let vessel = open(&path);
+
+        assert_eq!(timestamps(vessel.read_from(0).map(|v| v.unwrap())), expected);
+    }
+
+    #[test]
+    fn purge_removes_deleted_records() {
+        let dir = TempDir::new("vessel_purge");
+        let path = dir.join("stream");
+        let deleted = [(1000, 2000), (2500, 2600)];
+
+        let mut vessel = open(&path);
+        write(&mut vessel, 1, 3500);
+
+        for (from, to) in deleted {
+            vessel.delete_range(from, to).unwrap();
+        }
+
+        // Nothing is removed from the pages until the purge.
+        assert_eq!(stored(&path, 1000), Some(1000));
+        assert_eq!(stored(&path, 2000), Some(1000));
+
+        vessel.purge().unwrap();
+
+        assert!(!Layout::Flat.page_path(&path, 1000).exists());
+        assert_eq!(stored(&path, 0), Some(999));
+        assert_eq!(stored(&path, 2000), Some(900));
+        assert_eq!(timestamps(vessel.read_from(0).map(|v| v.unwrap())), outside(1, 3500, &deleted));
+
+        // Records written into a purged range afterwards aren't hidden.
+        vessel.upsert(Rc::new(vec![Blob::new(1500, 1.0)])).unwrap();
+        assert_eq!(timestamps(vessel.read_range(1000, 2000).map(|v| v.unwrap())), vec![1500]);
+    }
+
+    // Writes a full page and half of the next, flushed, then tears the end
+    // of the second page as a crash part way through a flush would.
+    fn torn_page(path: &PathBuf) -> PathBuf {
+        let mut vessel = open(path);
+        write(&mut vessel, 1, 1500);
+        vessel.flush().unwrap();
+        drop(vessel);
+
+        let page = Layout::Flat.page_path(path, 1000);
+        append_bytes(&page, &[7; 9]);
+
+        return page;
+    }
+
+    fn record_width() -> u64 {
+        return PageHeader::current(ValueType::F64).record_width() as u64;
     }
-}
\ No newline at end of file
+
+    fn assert_torn_page_repaired(path: &PathBuf, page: &PathBuf, mut vessel: Vessel) {
+        assert_eq!(fs::metadata(page).unwrap().len(), HEADER_SIZE as u64 + 500 * record_width());
+        assert_eq!(timestamps(vessel.read_from(0).map(|v| v.unwrap())), (1..1500).collect::<Vec<UnixTime>>());
+
+        // Later records are appended on a record boundary.
+        write(&mut vessel, 1500, 1600);
+        vessel.flush().unwrap();
+        drop(vessel);
+
+        let vessel = open(path);
+        assert_eq!(vessel.recovery().torn.len(), 0);
+        assert_eq!(timestamps(vessel.read_from(0).map(|v| v.unwrap())), (1..1600).collect::<Vec<UnixTime>>());
+    }
+
+    #[test]
+    fn torn_page_tail_is_truncated() {
+        let dir = TempDir::new("vessel_torn_truncate");
+        let path = dir.join("stream");
+        let page = torn_page(&path);
+
+        let vessel = open_with(&path, TornTailPolicy::Truncate);
+        let torn = vessel.recovery().torn.clone();
+
+        assert_eq!(torn.len(), 1);
+        assert_eq!(torn[0].path, page);
+        assert_eq!(torn[0].offset, HEADER_SIZE as u64 + 500 * record_width());
+        assert_eq!(torn[0].len, 9);
+        assert_eq!(torn[0].quarantine, None);
+
+        assert_torn_page_repaired(&path, &page, vessel);
+    }
+
+    #[test]
+    fn torn_page_tail_is_quarantined() {
+        let dir = TempDir::new("vessel_torn_quarantine");
+        let path = dir.join("stream");
+        let page = torn_page(&path);
+
+        let vessel = open_with(&path, TornTailPolicy::Quarantine);
+        let torn = vessel.recovery().torn.clone();
+
+        assert_eq!(torn.len(), 1);
+        assert_eq!(torn[0].len, 9);
+
+        let quarantine = torn[0].quarantine.clone().unwrap();
+        assert_eq!(fs::read(&quarantine).unwrap(), vec![7; 9]);
+
+        // The quarantined bytes aren't taken for a page.
+        assert_torn_page_repaired(&path, &page, vessel);
+        assert!(quarantine.exists());
+    }
+
+    // The log is only ever read for replay and then rewritten, so a tear
+    // in it is dropped whichever the policy, and never quarantined.
+    #[test]
+    fn torn_log_tail_is_dropped_under_either_policy() {
+        for torn_tail in [TornTailPolicy::Truncate, TornTailPolicy::Quarantine] {
+            let dir = TempDir::new("vessel_torn_log");
+            let path = dir.join("stream");
+
+            let mut vessel = open_with(&path, torn_tail);
+            write(&mut vessel, 1, 1500);
+            drop(vessel);
+
+            append_bytes(&path.join(WAL_FILE_NAME), &[7; 9]);
+
+            let mut vessel = open_with(&path, torn_tail);
+            assert_eq!(vessel.recovery().replayed, 500);
+            assert_eq!(vessel.recovery().torn.len(), 0);
+            assert_eq!(fs::metadata(path.join(WAL_FILE_NAME)).unwrap().len(), HEADER_SIZE as u64);
+
+            write(&mut vessel, 1500, 1600);
+            drop(vessel);
+
+            // Only what was written since the replay is left to replay.
+            let vessel = open_with(&path, torn_tail);
+            assert_eq!(vessel.recovery().replayed, 100);
+            assert_eq!(timestamps(vessel.read_from(0).map(|v| v.unwrap())), (1..1600).collect::<Vec<UnixTime>>());
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use log::warn;
+use crate::storage::domain::blob::Blob;
+use crate::storage::domain::data_page::DataPage;
+use crate::storage::domain::page_header::{HEADER_SIZE, PageError, PageHeader};
+use crate::storage::domain::value::ValueType;
+use crate::storage::durability::{Durability, sync_dir};
+use crate::storage::error::StorageError;
+
+pub const WAL_FILE_NAME: &str = "wal";
+
+// Holds the records accepted by a vessel which haven't yet been flushed to
+// a page file. It uses the same header and record encoding as a page, but
+// a torn record at the end is expected after a crash and is ignored.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: File,
+    header: PageHeader,
+    durability: Durability,
+    buf: Vec<u8>
+}
+
+impl WriteAheadLog {
+    pub fn open(dir: &PathBuf, value_type: ValueType, durability: Durability) -> Result<WriteAheadLog, PageError> {
+        let path = dir.join(WAL_FILE_NAME);
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(|e| PageError::io(&path, e))?;
+
+        let header = match PageHeader::read_from(&path, &mut file) {
+            Ok(Some(v)) => {
+                v.check_type(&path, value_type)?;
+                v
+            },
+            // A crash while the log was being created can leave less than a
+            // header behind, which can't have held any records.
+            Ok(None) | Err(PageError::TruncatedHeader { .. }) => {
+                let header = PageHeader::current(value_type);
+
+                file.set_len(0).map_err(|e| PageError::io(&path, e))?;
+                file.write_all(&header.encode()).map_err(|e| PageError::io(&path, e))?;
+
+                if durability != Durability::None {
+                    file.sync_data().map_err(|e| PageError::io(&path, e))?;
+                }
+
+                header
+            },
+            Err(e) => return Err(e)
+        };
+
+        return Ok(WriteAheadLog {
+            path,
+            file,
+            header,
+            durability,
+            buf: Vec::new()
+        });
+    }
+
+    // Returns every intact record in the log, in the order they were appended.
+    pub fn read(&self) -> Result<Vec<Blob>, PageError> {
+        let bytes = fs::read(&self.path).map_err(|e| PageError::io(&self.path, e))?;
+
+        let width = self.header.record_width();
+        let mut blobs = Vec::new();
+        let mut offset = HEADER_SIZE;
+
+        while offset + width <= bytes.len() {
+            let record = &bytes[offset..offset + width];
+
+            match self.header.decode_record(&self.path, offset as u64, record) {
+                Ok(v) => blobs.push(v),
+                Err(e) => {
+                    warn!("Ignoring the rest of {}: {}", self.path.display(), e);
+                    return Ok(blobs);
+                }
+            }
+
+            offset += width;
+        }
+
+        if offset < bytes.len() {
+            warn!("Ignoring {} bytes of torn record at the end of {}",
+                bytes.len() - offset, self.path.display());
+        }
+
+        return Ok(blobs);
+    }
+
+    pub fn append(&mut self, records: &[Blob]) -> Result<(), PageError> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        self.buf.clear();
+
+        for record in records {
+            self.header.encode_record(record, &mut self.buf);
+        }
+
+        return self.file
+            .write_all(&self.buf)
+            .map_err(|e| PageError::io(&self.path, e));
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        return &self.path;
+    }
+
+    pub fn file(&self) -> &File {
+        return &self.file;
+    }
+
+    // Drops everything that has made it into a page file, keeping only
+    // the records which are still buffered. The new log is written beside
+    // the old one and renamed over it, so that a crash part way through
+    // can't lose records which were only in the log.
+    pub fn reset(&mut self, pending: &[Blob]) -> Result<(), StorageError> {
+        let sync = self.durability != Durability::None;
+
+        if pending.is_empty() {
+            self.file
+                .set_len(HEADER_SIZE as u64)
+                .map_err(|e| StorageError::io(&self.path, e))?;
+
+            if sync {
+                self.file.sync_data().map_err(|e| StorageError::io(&self.path, e))?;
+            }
+
+            return Ok(());
+        }
+
+        let temp = DataPage::temp_path(&self.path);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)
+            .map_err(|e| StorageError::io(&temp, e))?;
+
+        self.buf.clear();
+        self.buf.extend_from_slice(&self.header.encode());
+
+        for record in pending {
+            self.header.encode_record(record, &mut self.buf);
+        }
+
+        file.write_all(&self.buf).map_err(|e| StorageError::io(&temp, e))?;
+
+        if sync {
+            file.sync_data().map_err(|e| StorageError::io(&temp, e))?;
+        }
+
+        fs::rename(&temp, &self.path).map_err(|e| StorageError::io(&self.path, e))?;
+
+        if sync {
+            sync_dir(&self.path.parent().unwrap().to_path_buf())?;
+        }
+
+        self.file = file;
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use crate::storage::descriptors::DescriptorCache;
+    use crate::storage::domain::blob::Blob;
+    use crate::storage::domain::bucket::Bucketing;
+    use crate::storage::domain::page_header::HEADER_SIZE;
+    use crate::storage::domain::value::ValueType;
+    use crate::storage::durability::Durability;
+    use crate::storage::layout::Layout;
+    use crate::storage::late_data::LatePolicy;
+    use crate::storage::recovery::TornTailPolicy;
+    use crate::storage::retention::Retention;
+    use crate::storage::testing::TempDir;
+    use crate::storage::vessel2::Vessel;
+    use super::{WAL_FILE_NAME, WriteAheadLog};
+
+    fn records(from: i64, count: i64) -> Vec<Blob> {
+        return (from..from + count).map(|t| Blob::new(t, t as f64 / 2.0)).collect();
+    }
+
+    fn assert_same(found: &[Blob], expected: &[Blob]) {
+        assert_eq!(found.len(), expected.len());
+
+        for (found, expected) in found.iter().zip(expected) {
+            assert_eq!(found.timestamp, expected.timestamp);
+            assert_eq!(found.data.to_bits(), expected.data.to_bits());
+        }
+    }
+
+    fn append_bytes(path: &PathBuf, bytes: &[u8]) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(bytes).unwrap();
+    }
+
+    fn open_vessel(path: &PathBuf) -> Vessel {
+        return Vessel::new(
+            path.clone(),
+            Bucketing::Fixed(1000),
+            LatePolicy::Reject,
+            Retention::Forever,
+            Durability::EveryFlush,
+            TornTailPolicy::Truncate,
+            ValueType::F64,
+            Layout::Flat,
+            Arc::new(DescriptorCache::new(8))).unwrap();
+    }
+
+    #[test]
+    fn torn_tail_is_ignored() {
+        let dir = TempDir::new("wal_torn");
+        let expected = records(1, 5);
+
+        let mut wal = WriteAheadLog::open(&dir.path(), ValueType::F64, Durability::EveryFlush).unwrap();
+        wal.append(&expected).unwrap();
+        drop(wal);
+
+        append_bytes(&dir.join(WAL_FILE_NAME), &[7; 9]);
+
+        let wal = WriteAheadLog::open(&dir.path(), ValueType::F64, Durability::EveryFlush).unwrap();
+        assert_same(&wal.read().unwrap(), &expected);
+    }
+
+    #[test]
+    fn short_header_is_an_empty_log() {
+        let dir = TempDir::new("wal_short");
+        fs::write(dir.join(WAL_FILE_NAME), &[1, 2, 3]).unwrap();
+
+        let mut wal = WriteAheadLog::open(&dir.path(), ValueType::F64, Durability::EveryFlush).unwrap();
+        assert_eq!(wal.read().unwrap().len(), 0);
+
+        let expected = records(1, 3);
+        wal.append(&expected).unwrap();
+        assert_same(&wal.read().unwrap(), &expected);
+    }
+
+    #[test]
+    fn reset_keeps_only_pending() {
+        let dir = TempDir::new("wal_reset");
+
+        let mut wal = WriteAheadLog::open(&dir.path(), ValueType::F64, Durability::EveryFlush).unwrap();
+        wal.append(&records(1, 10)).unwrap();
+
+        wal.reset(&records(8, 3)).unwrap();
+        assert_same(&wal.read().unwrap(), &records(8, 3));
+
+        wal.append(&records(11, 2)).unwrap();
+        assert_same(&wal.read().unwrap(), &records(8, 5));
+
+        wal.reset(&[]).unwrap();
+        assert_eq!(wal.read().unwrap().len(), 0);
+        assert_eq!(fs::metadata(dir.join(WAL_FILE_NAME)).unwrap().len(), HEADER_SIZE as u64);
+    }
+
+    #[test]
+    fn unflushed_records_are_replayed_after_a_crash() {
+        let dir = TempDir::new("wal_replay");
+        let path = dir.join("stream");
+
+        // The first page is turned, and so flushed, while the rest of the
+        // records are only in the write buffer and the log.
+        let mut vessel = open_vessel(&path);
+        vessel.write(Rc::new(records(1, 1500))).unwrap();
+        drop(vessel);
+
+        let vessel = open_vessel(&path);
+        assert_eq!(vessel.recovery().replayed, 501);
+        assert_eq!(vessel.get_last_time().unwrap(), 1500);
+
+        let found = vessel.read_from(0).flat_map(|v| v.unwrap()).collect::<Vec<Blob>>();
+        assert_same(&found, &records(1, 1500));
+    }
+
+    #[test]
+    fn records_in_a_torn_log_are_replayed_up_to_the_tear() {
+        let dir = TempDir::new("wal_replay_torn");
+        let path = dir.join("stream");
+
+        let mut vessel = open_vessel(&path);
+        vessel.write(Rc::new(records(1, 20))).unwrap();
+        drop(vessel);
+
+        append_bytes(&path.join(WAL_FILE_NAME), &[7; 5]);
+
+        let mut vessel = open_vessel(&path);
+        assert_eq!(vessel.recovery().replayed, 20);
+
+        // The log is rewritten without the tear, so later records line up.
+        vessel.write(Rc::new(records(21, 5))).unwrap();
+        drop(vessel);
+
+        let vessel = open_vessel(&path);
+        let found = vessel.read_from(0).flat_map(|v| v.unwrap()).collect::<Vec<Blob>>();
+        assert_same(&found, &records(1, 25));
+    }
+}
@@ -3,6 +3,7 @@ use tokio::time::interval;
 use crate::{Blob};
 use crate::domain::UnixTime;
 use crate::storage::domain::bucket::Bucket;
+use crate::storage::domain::value::Value;
 
 #[derive(Clone, Eq, Hash, PartialEq)]
 pub enum Calc {
@@ -14,7 +15,7 @@ pub enum Calc {
 
 
 pub struct Entry {
-    item: Option<f64>,
+    item: Option<Value>,
     timestamp: UnixTime
 }
 
@@ -33,6 +34,21 @@ impl Aggregator {
         return Aggregator { calc, size: (size as i64) * (interval as i64), max, entry: None};
     }
 
+    // An aggregator with the same settings, but nothing accumulated.
+    pub fn fresh(&self) -> Aggregator {
+        return Aggregator { calc: self.calc.clone(), size: self.size, max: self.max, entry: None };
+    }
+
+    // The start of the window being built up, if there is one.
+    pub fn open_window(&self) -> Option<UnixTime> {
+        return self.entry.as_ref().map(|v| v.timestamp);
+    }
+
+    // The length of time each output record covers.
+    pub fn window(&self) -> i64 {
+        return self.size;
+    }
+
     pub fn add(&mut self, blob: Blob) -> Option<Blob> {
         let idx = blob.timestamp % self.size;
 
@@ -4,6 +4,10 @@ use crate::domain::UnixTime;
 
 use crate::streaming::domain::{Aggregator, Calc};
 use crate::streaming::streams::stream::Stream;
+use crate::storage::domain::mapped_page::PageView;
+use crate::storage::domain::value::ValueType;
+use crate::storage::error::StorageError;
+use crate::storage::late_data::LateDataCounters;
 
 pub struct AggregateStream {
     pub stream_def: StreamRef,
@@ -20,29 +24,100 @@ impl AggregateStream {
             buf: Aggregator::new(calc, count, interval)
         }
     }
+
+    fn aggregate(&mut self, input: impl Iterator<Item=Blob>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        let mut output = vec!();
+
+        for record in input {
+            if let Some(val) = self.buf.add(record){
+                output.push(val);
+            }
+        }
+
+        return Ok(Rc::new(self.vessel.write(Rc::new(output))?));
+    }
 }
 
 impl Stream for AggregateStream {
-    fn replay(&mut self, since: UnixTime) -> Box<dyn Iterator<Item=Vec<Blob>>> {
-        return Box::new(self.vessel.read_from(since));
+    fn replay(&mut self, since: UnixTime) -> Box<dyn Iterator<Item=Result<PageView, StorageError>>> {
+        return Box::new(self.vessel.view_from(since));
+    }
+
+    fn read_range(&self, from: UnixTime, to: UnixTime) -> Box<dyn Iterator<Item=Result<PageView, StorageError>>> {
+        return Box::new(self.vessel.view_range(from, to));
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        return self.vessel.flush();
+    }
+
+    fn tick(&mut self) -> Result<(), StorageError> {
+        return self.vessel.tick();
+    }
+
+    fn compact(&mut self) -> Result<(), StorageError> {
+        return self.vessel.compact();
+    }
+
+    fn enforce_retention(&mut self) -> Result<(), StorageError> {
+        return self.vessel.enforce_retention();
     }
 
-    fn flush(&mut self) {
-        self.vessel.flush();
+    fn delete_range(&mut self, from: UnixTime, to: UnixTime) -> Result<(), StorageError> {
+        return self.vessel.delete_range(from, to);
     }
 
-    fn on_next(&mut self, source: StreamRef, input: Rc<Vec<Blob>>) -> Rc<Vec<Blob>> {
+    fn late_data(&self) -> LateDataCounters {
+        return self.vessel.late_data();
+    }
+
+    fn value_type(&self) -> ValueType {
+        return self.vessel.value_type();
+    }
+
+    fn upsert(&mut self, batch: Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        return Ok(Rc::new(self.vessel.upsert(batch)?));
+    }
+
+    // A correction changes the whole window it falls in.
+    fn affected_range(&self, from: UnixTime, to: UnixTime) -> (UnixTime, UnixTime) {
+        let window = self.buf.window();
+        return (from - from.rem_euclid(window), to - to.rem_euclid(window) + window);
+    }
+
+    fn on_correction(&mut self, inputs: Vec<(StreamRef, Vec<Blob>)>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        let open = self.buf.open_window();
+        let mut reaches_open = false;
+
+        let mut aggregator = self.buf.fresh();
         let mut output = vec!();
 
-        for record in input.iter() {
-            if let Some(val) = self.buf.add(record.clone()){
-                output.push(val);
+        for (_, batch) in inputs {
+            for record in batch {
+                reaches_open |= open.map_or(false, |v| record.timestamp >= v);
+
+                if let Some(val) = aggregator.add(record) {
+                    output.push(val);
+                }
             }
         }
 
-        let records = Rc::new(output);
-        self.vessel.write(records.clone());
-        return records.clone();
+        // The inputs cover whole windows, so if they reach the window being
+        // built up they hold all of it. It's rebuilt from them, rather than
+        // closing later with what was there before the correction.
+        if reaches_open {
+            self.buf = aggregator;
+        }
+
+        return self.upsert(Rc::new(output));
+    }
+
+    fn on_next(&mut self, source: StreamRef, input: Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        return self.aggregate(input.iter().cloned());
+    }
+
+    fn on_replay(&mut self, _source: StreamRef, page: PageView) -> Result<Rc<Vec<Blob>>, StorageError> {
+        return self.aggregate(page.iter());
     }
 }
 
@@ -2,6 +2,10 @@ use std::rc::Rc;
 use crate::{Blob, StreamDefinition, StreamRef, Vessel};
 use crate::domain::UnixTime;
 use crate::streaming::streams::stream::Stream;
+use crate::storage::domain::mapped_page::PageView;
+use crate::storage::domain::value::ValueType;
+use crate::storage::error::StorageError;
+use crate::storage::late_data::LateDataCounters;
 
 pub struct BasicStream {
     pub stream_def: StreamRef,
@@ -18,16 +22,65 @@ impl BasicStream {
 }
 
 impl Stream for BasicStream {
-    fn replay(&mut self, since: UnixTime) -> Box<dyn Iterator<Item=Vec<Blob>>> {
-        return Box::new(self.vessel.read_from(since));
+    fn replay(&mut self, since: UnixTime) -> Box<dyn Iterator<Item=Result<PageView, StorageError>>> {
+        return Box::new(self.vessel.view_from(since));
     }
 
-    fn flush(&mut self) {
-        self.vessel.flush();
+    fn read_range(&self, from: UnixTime, to: UnixTime) -> Box<dyn Iterator<Item=Result<PageView, StorageError>>> {
+        return Box::new(self.vessel.view_range(from, to));
     }
 
-    fn on_next(&mut self, source: StreamRef, record: Rc<Vec<Blob>>) -> Rc<Vec<Blob>> {
-        self.vessel.write(record.clone());
-        return record;
+    fn flush(&mut self) -> Result<(), StorageError> {
+        return self.vessel.flush();
+    }
+
+    fn tick(&mut self) -> Result<(), StorageError> {
+        return self.vessel.tick();
+    }
+
+    fn compact(&mut self) -> Result<(), StorageError> {
+        return self.vessel.compact();
+    }
+
+    fn enforce_retention(&mut self) -> Result<(), StorageError> {
+        return self.vessel.enforce_retention();
+    }
+
+    fn delete_range(&mut self, from: UnixTime, to: UnixTime) -> Result<(), StorageError> {
+        return self.vessel.delete_range(from, to);
+    }
+
+    fn late_data(&self) -> LateDataCounters {
+        return self.vessel.late_data();
+    }
+
+    fn value_type(&self) -> ValueType {
+        return self.vessel.value_type();
+    }
+
+    fn upsert(&mut self, batch: Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        return Ok(Rc::new(self.vessel.upsert(batch)?));
+    }
+
+    fn affected_range(&self, from: UnixTime, to: UnixTime) -> (UnixTime, UnixTime) {
+        return (from, to.saturating_add(1));
+    }
+
+    fn on_correction(&mut self, inputs: Vec<(StreamRef, Vec<Blob>)>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        let records = inputs
+            .into_iter()
+            .flat_map(|(_, batch)| batch)
+            .collect::<Vec<Blob>>();
+
+        return self.upsert(Rc::new(records));
+    }
+
+    fn on_next(&mut self, source: StreamRef, record: Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        return Ok(Rc::new(self.vessel.write(record)?));
+    }
+
+    // Stored as they are, so need a batch of their own anyway.
+    fn on_replay(&mut self, source: StreamRef, page: PageView) -> Result<Rc<Vec<Blob>>, StorageError> {
+        return self.on_next(source, Rc::new(page.into_vec()));
     }
 }
@@ -3,6 +3,10 @@ use std::rc::Rc;
 use crate::{Blob, StreamDefinition, StreamKind, StreamRef, UnixTime, Vessel};
 use crate::data_structures::domain::MergedStreamRef;
 use crate::streaming::streams::stream::Stream;
+use crate::storage::domain::mapped_page::PageView;
+use crate::storage::domain::value::ValueType;
+use crate::storage::error::StorageError;
+use crate::storage::late_data::LateDataCounters;
 
 pub struct StreamBuffer {
     pub items: HashMap<UnixTime, HashMap<StreamRef, Blob>>,
@@ -58,32 +62,88 @@ impl MergedStream {
             vessel
         }
     }
+
+    fn merge(&mut self, source: StreamRef, input: impl Iterator<Item=Blob>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        let mut mapped = vec![];
+
+        for item in input {
+            let merge_result = self.sources.add(source, item);
+
+            if let Some(result) = merge_result {
+                let record = self.merge_func.get_func(result);
+                mapped.push(record);
+            }
+        }
+
+        return Ok(Rc::new(self.vessel.write(Rc::new(mapped))?));
+    }
 }
 
 impl Stream for MergedStream {
-    fn replay(&mut self, since: UnixTime) -> Box<dyn Iterator<Item=Vec<Blob>>> {
-        return Box::new(self.vessel.read_from(since));
+    fn replay(&mut self, since: UnixTime) -> Box<dyn Iterator<Item=Result<PageView, StorageError>>> {
+        return Box::new(self.vessel.view_from(since));
     }
 
-    fn flush(&mut self) {
-        self.vessel.flush();
+    fn read_range(&self, from: UnixTime, to: UnixTime) -> Box<dyn Iterator<Item=Result<PageView, StorageError>>> {
+        return Box::new(self.vessel.view_range(from, to));
     }
 
-    fn on_next(&mut self, source: StreamRef, record: Rc<Vec<Blob>>) -> Rc<Vec<Blob>> {
-        let mut mapped = vec![];
+    fn flush(&mut self) -> Result<(), StorageError> {
+        return self.vessel.flush();
+    }
 
-        for item in &*record {
-            let merge_result = self.sources.add(source, item.clone());
+    fn tick(&mut self) -> Result<(), StorageError> {
+        return self.vessel.tick();
+    }
 
-            if let Some(result) = merge_result {
-                let record = self.merge_func.get_func(result);
-                mapped.push(record);
+    fn compact(&mut self) -> Result<(), StorageError> {
+        return self.vessel.compact();
+    }
+
+    fn enforce_retention(&mut self) -> Result<(), StorageError> {
+        return self.vessel.enforce_retention();
+    }
+
+    fn delete_range(&mut self, from: UnixTime, to: UnixTime) -> Result<(), StorageError> {
+        return self.vessel.delete_range(from, to);
+    }
+
+    fn late_data(&self) -> LateDataCounters {
+        return self.vessel.late_data();
+    }
+
+    fn value_type(&self) -> ValueType {
+        return self.vessel.value_type();
+    }
+
+    fn upsert(&mut self, batch: Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        return Ok(Rc::new(self.vessel.upsert(batch)?));
+    }
+
+    fn affected_range(&self, from: UnixTime, to: UnixTime) -> (UnixTime, UnixTime) {
+        return (from, to.saturating_add(1));
+    }
+
+    fn on_correction(&mut self, inputs: Vec<(StreamRef, Vec<Blob>)>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        let mut sources = StreamBuffer::new(self.merge_func.len());
+        let mut mapped = vec![];
+
+        for (source, batch) in inputs {
+            for item in batch {
+                if let Some(result) = sources.add(source, item) {
+                    mapped.push(self.merge_func.get_func(result));
+                }
             }
         }
 
-        let mapped = Rc::new(mapped);
-        self.vessel.write(mapped.clone());
+        return self.upsert(Rc::new(mapped));
+    }
+
+    fn on_next(&mut self, source: StreamRef, record: Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError> {
+        return self.merge(source, record.iter().cloned());
+    }
 
-        return mapped;
+    fn on_replay(&mut self, source: StreamRef, page: PageView) -> Result<Rc<Vec<Blob>>, StorageError> {
+        return self.merge(source, page.iter());
     }
 }
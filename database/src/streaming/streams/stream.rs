@@ -3,11 +3,44 @@ use crate::{Blob, StreamDefinition, StreamKind, StreamRef, UnixTime, Vessel};
 use crate::streaming::streams::aggregate_stream::AggregateStream;
 use crate::streaming::streams::basic_stream::BasicStream;
 use crate::streaming::streams::merged_stream::MergedStream;
+use crate::storage::domain::mapped_page::PageView;
+use crate::storage::domain::value::ValueType;
+use crate::storage::error::StorageError;
+use crate::storage::late_data::LateDataCounters;
 
 pub trait Stream {
-    fn replay(&mut self, since: UnixTime) -> Box<dyn Iterator<Item=Vec<Blob>>>;
-    fn flush(&mut self);
-    fn on_next(&mut self, source: StreamRef, batch: Rc<Vec<Blob>>) -> Rc<Vec<Blob>>;
+    // Both read a page at a time, without copying pages out of their
+    // mappings.
+    fn replay(&mut self, since: UnixTime) -> Box<dyn Iterator<Item=Result<PageView, StorageError>>>;
+    fn read_range(&self, from: UnixTime, to: UnixTime) -> Box<dyn Iterator<Item=Result<PageView, StorageError>>>;
+    fn flush(&mut self) -> Result<(), StorageError>;
+    fn tick(&mut self) -> Result<(), StorageError>;
+    fn compact(&mut self) -> Result<(), StorageError>;
+    fn enforce_retention(&mut self) -> Result<(), StorageError>;
+
+    // Deletes the stored records in [from, to).
+    fn delete_range(&mut self, from: UnixTime, to: UnixTime) -> Result<(), StorageError>;
+    fn late_data(&self) -> LateDataCounters;
+    fn value_type(&self) -> ValueType;
+    // Stores whatever the batch produces, returning the records which were
+    // stored for the stream's children. If some were rejected as late the
+    // error holds the rest, which still go on.
+    fn on_next(&mut self, source: StreamRef, batch: Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError>;
+
+    // As on_next, for a page of a source's stored records being replayed,
+    // which is read where it lies rather than copied into a batch first.
+    fn on_replay(&mut self, source: StreamRef, page: PageView) -> Result<Rc<Vec<Blob>>, StorageError>;
+
+    // Replaces stored records, returning those which changed.
+    fn upsert(&mut self, batch: Rc<Vec<Blob>>) -> Result<Rc<Vec<Blob>>, StorageError>;
+
+    // The range of input, [from, to), needed to recompute everything
+    // affected by corrections to inputs between from and to inclusive.
+    fn affected_range(&self, from: UnixTime, to: UnixTime) -> (UnixTime, UnixTime);
+
+    // Recomputes from the corrected range of every source, upserting
+    // and returning whatever output changed.
+    fn on_correction(&mut self, inputs: Vec<(StreamRef, Vec<Blob>)>) -> Result<Rc<Vec<Blob>>, StorageError>;
 }
 
 pub fn create_stream(stream_def: StreamRef, vessel: Vessel) -> Box<dyn Stream> {